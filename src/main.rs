@@ -1,8 +1,15 @@
 use std::{collections::BTreeMap, path::PathBuf, str::FromStr};
 
-use bitcoin::{bip32::ExtendedPrivKey, block, Address};
+use bitcoin::bip32::ExtendedPrivKey;
 use bitcoind::bitcoincore_rpc::{Auth, Client, RpcApi};
-use wallet::{error::WalletError, keychain_kind::KeychainKind, store::WalletStore};
+use wallet::{
+    backend::BackendKind,
+    coin_selection::CoinSelectionAlgorithm,
+    error::WalletError,
+    fee_rate::FeeRate,
+    keychain_kind::KeychainKind,
+    store::{Birthday, WalletStore},
+};
 
 pub mod wallet;
 
@@ -46,6 +53,11 @@ fn main() -> Result<(), WalletError> {
         client,
         file_name,
         path,
+        BackendKind::File,
+        None,
+        // the seed phrase is restored on a regtest chain started after it was generated,
+        // so every block could pay to it
+        Some(Birthday::Height(0)),
     )?;
 
     let wallet_name = store.file_name.to_str().unwrap().to_string();
@@ -74,12 +86,32 @@ fn main() -> Result<(), WalletError> {
     //     1,
     // )?;
 
+    // a wallet restored from its seed phrase has not seen any blocks yet
+    if store.latest_checkpoint().height == 0 {
+        let stop_gap = BTreeMap::from([(KeychainKind::External, 20), (KeychainKind::Internal, 20)]);
+
+        store.full_scan(None, stop_gap, |height, tip_height| {
+            println!("Scanned block {} of {}", height, tip_height)
+        })?;
+    }
+
+    store.sync()?;
+    store.sync_mempool()?;
+
     let address = store.get_address()?;
 
     println!("{}", address);
     println!("{}", address.script_pubkey());
 
-    store.send_to_address(store.get_address()?, 10000)?;
+    let details = store.send_to_address(
+        store.get_address()?,
+        10000,
+        FeeRate::ConfirmationTarget(6),
+        CoinSelectionAlgorithm::default(),
+        &[],
+    )?;
+
+    println!("sent: {:?}", details);
 
     // store.generate_to_address();
 
@@ -0,0 +1,54 @@
+use bdk_chain::PersistBackend;
+
+use super::{
+    error::WalletError, keychain_store::KeychainStore, sqlite_store::SqliteStore, store::Changeset,
+};
+
+/// Which storage `WalletStore::new` persists the wallet to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendKind {
+    /// An append-only log of changesets, see `KeychainStore`.
+    File,
+    Sqlite,
+}
+
+pub enum WalletBackend {
+    File(KeychainStore),
+    Sqlite(SqliteStore),
+}
+
+impl WalletBackend {
+    pub fn try_clone(&self) -> Result<Self, WalletError> {
+        match self {
+            WalletBackend::File(store) => Ok(WalletBackend::File(store.clone())),
+            WalletBackend::Sqlite(store) => Ok(WalletBackend::Sqlite(store.try_clone()?)),
+        }
+    }
+
+    /// Whether changesets are written encrypted, so secret keys may go in them.
+    pub fn is_encrypted(&self) -> bool {
+        match self {
+            WalletBackend::File(store) => store.is_encrypted(),
+            WalletBackend::Sqlite(_) => false,
+        }
+    }
+}
+
+impl PersistBackend<Changeset> for WalletBackend {
+    type LoadError = WalletError;
+    type WriteError = WalletError;
+
+    fn write_changes(&mut self, changeset: &Changeset) -> Result<(), Self::WriteError> {
+        match self {
+            WalletBackend::File(store) => store.write_changes(changeset),
+            WalletBackend::Sqlite(store) => store.write_changes(changeset),
+        }
+    }
+
+    fn load_from_persistence(&mut self) -> Result<Option<Changeset>, Self::LoadError> {
+        match self {
+            WalletBackend::File(store) => store.load_from_persistence(),
+            WalletBackend::Sqlite(store) => store.load_from_persistence(),
+        }
+    }
+}
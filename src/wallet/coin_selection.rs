@@ -0,0 +1,477 @@
+use std::cmp::Reverse;
+
+use bitcoin::{OutPoint, TxOut};
+use rand::seq::SliceRandom;
+
+use super::{error::WalletError, fee_rate::MIN_RELAY_FEE_RATE};
+
+/// Weight of an input before its scriptSig and witness are filled in: outpoint, sequence
+/// and an empty scriptSig length byte.
+pub const TXIN_BASE_WEIGHT: usize = (32 + 4 + 4 + 1) * 4;
+
+/// Branch-and-bound gives up after exploring this many nodes, like bitcoind.
+const BNB_TOTAL_TRIES: usize = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CoinSelectionAlgorithm {
+    /// Looks for an input set that needs no change output, falling back to
+    /// `SingleRandomDraw` when there is none.
+    #[default]
+    BranchAndBound,
+    LargestFirst,
+    SingleRandomDraw,
+}
+
+#[derive(Debug, Clone)]
+pub struct WeightedUtxo {
+    pub outpoint: OutPoint,
+    pub txout: TxOut,
+    /// Weight the spending input's scriptSig and witness add, see `max_weight_to_satisfy`.
+    pub satisfaction_weight: usize,
+}
+
+impl WeightedUtxo {
+    pub fn new(outpoint: OutPoint, txout: TxOut, satisfaction_weight: usize) -> Self {
+        Self {
+            outpoint,
+            txout,
+            satisfaction_weight,
+        }
+    }
+
+    fn weight(&self) -> usize {
+        TXIN_BASE_WEIGHT + self.satisfaction_weight
+    }
+
+    /// Value left over after paying for the input itself at `fee_rate`.
+    fn effective_value(&self, fee_rate: f32) -> i64 {
+        self.txout.value as i64 - fee_for_weight(fee_rate, self.weight()) as i64
+    }
+}
+
+/// What the transaction has to pay for besides its inputs.
+#[derive(Debug, Clone)]
+pub struct CoinSelectionParams {
+    /// Sum of the recipient outputs.
+    pub target: u64,
+    /// In sat/vB.
+    pub fee_rate: f32,
+    /// Paid on top of `fee_rate`, usually with a `fee_rate` of zero.
+    pub absolute_fee: Option<u64>,
+    /// Fee of the transactions a replacement evicts. The replacement pays at least that
+    /// plus the minimum relay fee for itself (BIP125 rules 3 and 4).
+    pub replaced_fee: Option<u64>,
+    /// Weight of the transaction with its recipient outputs but no inputs and no change.
+    pub base_weight: usize,
+    /// Weight a change output adds to the transaction.
+    pub change_weight: usize,
+    /// Weight of the input that will later spend the change output.
+    pub change_spend_weight: usize,
+    /// Change below this is added to the fee instead.
+    pub dust_limit: u64,
+}
+
+impl CoinSelectionParams {
+    /// Fee of a whole transaction of `weight`.
+    fn fee(&self, weight: usize) -> u64 {
+        let fee = self.absolute_fee.unwrap_or(0) + fee_for_weight(self.fee_rate, weight);
+
+        match self.replaced_fee {
+            Some(replaced_fee) => {
+                fee.max(replaced_fee + fee_for_weight(MIN_RELAY_FEE_RATE, weight))
+            }
+            None => fee,
+        }
+    }
+
+    /// Creating change only pays off if it is worth more than it costs to create and spend.
+    fn cost_of_change(&self) -> u64 {
+        fee_for_weight(self.fee_rate, self.change_weight)
+            + fee_for_weight(self.fee_rate, self.change_spend_weight)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CoinSelectionResult {
+    pub selected: Vec<WeightedUtxo>,
+    pub fee: u64,
+    /// Value of the change output, if one should be added.
+    pub change: Option<u64>,
+}
+
+impl CoinSelectionResult {
+    pub fn selected_amount(&self) -> u64 {
+        self.selected.iter().map(|utxo| utxo.txout.value).sum()
+    }
+}
+
+pub fn fee_for_weight(fee_rate: f32, weight: usize) -> u64 {
+    (fee_rate as f64 * weight as f64 / 4.0).ceil() as u64
+}
+
+/// Picks inputs from `utxos` that pay for `params.target` and the fee of the resulting
+/// transaction. `required` are always spent, whichever algorithm is used.
+pub fn select_coins(
+    algorithm: CoinSelectionAlgorithm,
+    required: Vec<WeightedUtxo>,
+    utxos: Vec<WeightedUtxo>,
+    params: &CoinSelectionParams,
+) -> Result<CoinSelectionResult, WalletError> {
+    match algorithm {
+        CoinSelectionAlgorithm::BranchAndBound => {
+            match branch_and_bound(required.clone(), utxos.clone(), params) {
+                Some(result) => Ok(result),
+                None => single_random_draw(required, utxos, params),
+            }
+        }
+        CoinSelectionAlgorithm::LargestFirst => largest_first(required, utxos, params),
+        CoinSelectionAlgorithm::SingleRandomDraw => single_random_draw(required, utxos, params),
+    }
+}
+
+fn largest_first(
+    required: Vec<WeightedUtxo>,
+    mut utxos: Vec<WeightedUtxo>,
+    params: &CoinSelectionParams,
+) -> Result<CoinSelectionResult, WalletError> {
+    utxos.sort_by_key(|utxo| Reverse(utxo.txout.value));
+
+    select_in_order(required, utxos, params)
+}
+
+fn single_random_draw(
+    required: Vec<WeightedUtxo>,
+    utxos: Vec<WeightedUtxo>,
+    params: &CoinSelectionParams,
+) -> Result<CoinSelectionResult, WalletError> {
+    // inputs that cost more than they are worth would only be drawn to be paid for
+    let mut utxos = utxos
+        .into_iter()
+        .filter(|utxo| utxo.effective_value(params.fee_rate) > 0)
+        .collect::<Vec<_>>();
+    utxos.shuffle(&mut rand::thread_rng());
+
+    select_in_order(required, utxos, params)
+}
+
+/// Adds `utxos` one by one until the selection pays for the target and fee.
+fn select_in_order(
+    mut selected: Vec<WeightedUtxo>,
+    utxos: Vec<WeightedUtxo>,
+    params: &CoinSelectionParams,
+) -> Result<CoinSelectionResult, WalletError> {
+    let mut utxos = utxos.into_iter();
+
+    loop {
+        if let Some(result) = finish(selected.clone(), params, true) {
+            return Ok(result);
+        }
+
+        match utxos.next() {
+            Some(utxo) => selected.push(utxo),
+            None => {
+                return Err(WalletError::InsufficientFunds {
+                    needed: params.target + params.fee(weight(&selected, params)),
+                    available: selected.iter().map(|utxo| utxo.txout.value).sum(),
+                })
+            }
+        }
+    }
+}
+
+/// Searches for a selection whose effective value exceeds the target by less than the cost
+/// of change, so the transaction needs no change output. Based on bitcoind's `SelectCoinsBnB`.
+fn branch_and_bound(
+    required: Vec<WeightedUtxo>,
+    utxos: Vec<WeightedUtxo>,
+    params: &CoinSelectionParams,
+) -> Option<CoinSelectionResult> {
+    let required_value: i64 = required
+        .iter()
+        .map(|utxo| utxo.effective_value(params.fee_rate))
+        .sum();
+    let target = (params.target + params.fee(params.base_weight)) as i64 - required_value;
+    let cost_of_change = params.cost_of_change() as i64;
+
+    let mut pool = utxos
+        .into_iter()
+        .map(|utxo| (utxo.effective_value(params.fee_rate), utxo))
+        .filter(|(effective_value, _)| *effective_value > 0)
+        .collect::<Vec<_>>();
+    pool.sort_by_key(|(effective_value, _)| Reverse(*effective_value));
+
+    let mut available: i64 = pool
+        .iter()
+        .map(|(effective_value, _)| effective_value)
+        .sum();
+    if available < target {
+        return None;
+    }
+
+    let mut value = 0;
+    let mut selection: Vec<usize> = vec![];
+    let mut best: Option<(i64, Vec<usize>)> = None;
+    let mut index = 0;
+
+    for _ in 0..BNB_TOTAL_TRIES {
+        let mut backtrack = false;
+
+        if value + available < target || value > target + cost_of_change {
+            backtrack = true;
+        } else if value >= target {
+            let waste = value - target;
+            if best
+                .as_ref()
+                .is_none_or(|(best_waste, _)| waste <= *best_waste)
+            {
+                best = Some((waste, selection.clone()));
+            }
+            backtrack = true;
+        }
+
+        if backtrack {
+            let Some(&last) = selection.last() else {
+                break;
+            };
+
+            // undo everything after the last included utxo, then try omitting it instead
+            index -= 1;
+            while index > last {
+                available += pool[index].0;
+                index -= 1;
+            }
+            value -= pool[index].0;
+            selection.pop();
+        } else {
+            available -= pool[index].0;
+
+            // including a utxo worth the same as an omitted one explores the same subsets
+            let previous_included = selection.last().is_none_or(|&last| last + 1 == index);
+            if previous_included || pool[index].0 != pool[index - 1].0 {
+                selection.push(index);
+                value += pool[index].0;
+            }
+        }
+
+        index += 1;
+    }
+
+    let (_, best) = best?;
+    let mut selected = required;
+    selected.extend(best.into_iter().map(|index| pool[index].1.clone()));
+
+    finish(selected, params, false)
+}
+
+fn weight(selected: &[WeightedUtxo], params: &CoinSelectionParams) -> usize {
+    params.base_weight + selected.iter().map(WeightedUtxo::weight).sum::<usize>()
+}
+
+/// Works out fee and change for `selected`, or `None` if it does not cover the target.
+fn finish(
+    selected: Vec<WeightedUtxo>,
+    params: &CoinSelectionParams,
+    allow_change: bool,
+) -> Option<CoinSelectionResult> {
+    let selected_amount: u64 = selected.iter().map(|utxo| utxo.txout.value).sum();
+    let weight = weight(&selected, params);
+
+    let fee = params.fee(weight);
+    let excess = selected_amount.checked_sub(params.target + fee)?;
+
+    let fee_with_change = params.fee(weight + params.change_weight);
+    let change = selected_amount
+        .checked_sub(params.target + fee_with_change)
+        .filter(|change| allow_change && *change >= params.dust_limit);
+
+    match change {
+        Some(change) => Some(CoinSelectionResult {
+            selected,
+            fee: fee_with_change,
+            change: Some(change),
+        }),
+        // whatever is left over goes to the miners
+        None => Some(CoinSelectionResult {
+            selected,
+            fee: fee + excess,
+            change: None,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{hashes::Hash, ScriptBuf, Txid};
+
+    use super::*;
+
+    /// A P2WPKH input weighs 272 WU, 68 sats at 1 sat/vB.
+    const SATISFACTION_WEIGHT: usize = 108;
+    const INPUT_FEE: u64 = 68;
+
+    fn utxo(vout: u32, value: u64) -> WeightedUtxo {
+        let txout = TxOut {
+            value,
+            script_pubkey: ScriptBuf::new(),
+        };
+        WeightedUtxo::new(
+            OutPoint::new(Txid::all_zeros(), vout),
+            txout,
+            SATISFACTION_WEIGHT,
+        )
+    }
+
+    /// 1 sat/vB, with a base transaction costing 50 sats and a P2WPKH change output.
+    fn params(target: u64) -> CoinSelectionParams {
+        CoinSelectionParams {
+            target,
+            fee_rate: 1.0,
+            absolute_fee: None,
+            replaced_fee: None,
+            base_weight: 200,
+            change_weight: 31 * 4,
+            change_spend_weight: TXIN_BASE_WEIGHT + SATISFACTION_WEIGHT,
+            dust_limit: 294,
+        }
+    }
+
+    fn vouts(result: &CoinSelectionResult) -> Vec<u32> {
+        let mut vouts = result
+            .selected
+            .iter()
+            .map(|utxo| utxo.outpoint.vout)
+            .collect::<Vec<_>>();
+        vouts.sort();
+        vouts
+    }
+
+    #[test]
+    fn branch_and_bound_finds_exact_match() {
+        // 0 and 1 are worth exactly the target and base fee after paying for themselves
+        let utxos = vec![
+            utxo(0, 60_000 + INPUT_FEE),
+            utxo(1, 40_050 + INPUT_FEE),
+            utxo(2, 150_000),
+            utxo(3, 10_000),
+        ];
+
+        let result = select_coins(
+            CoinSelectionAlgorithm::BranchAndBound,
+            vec![],
+            utxos,
+            &params(100_000),
+        )
+        .unwrap();
+
+        assert_eq!(vouts(&result), vec![0, 1]);
+        assert_eq!(result.change, None);
+        assert_eq!(result.fee, 50 + 2 * INPUT_FEE);
+    }
+
+    #[test]
+    fn branch_and_bound_skips_equal_values() {
+        let utxos = (0..50).map(|vout| utxo(vout, 10_000 + INPUT_FEE)).collect();
+
+        let result = select_coins(
+            CoinSelectionAlgorithm::BranchAndBound,
+            vec![],
+            utxos,
+            &params(30_000 - 50),
+        )
+        .unwrap();
+
+        assert_eq!(result.selected.len(), 3);
+        assert_eq!(result.change, None);
+    }
+
+    #[test]
+    fn branch_and_bound_falls_back_to_single_random_draw() {
+        // no subset lands within the cost of change of the target
+        let result = select_coins(
+            CoinSelectionAlgorithm::BranchAndBound,
+            vec![],
+            vec![utxo(0, 200_000)],
+            &params(100_000),
+        )
+        .unwrap();
+
+        assert_eq!(vouts(&result), vec![0]);
+        let change = result.change.unwrap();
+        assert_eq!(100_000 + result.fee + change, 200_000);
+    }
+
+    #[test]
+    fn insufficient_funds_reports_amounts() {
+        let err = select_coins(
+            CoinSelectionAlgorithm::LargestFirst,
+            vec![],
+            vec![utxo(0, 10_000), utxo(1, 20_000)],
+            &params(50_000),
+        )
+        .unwrap_err();
+
+        assert!(matches!(
+            err,
+            WalletError::InsufficientFunds {
+                needed: 50_186,
+                available: 30_000,
+            }
+        ));
+    }
+
+    #[test]
+    fn dust_change_goes_to_fee() {
+        // 251 sats would be left as change, below the dust limit
+        let result = select_coins(
+            CoinSelectionAlgorithm::LargestFirst,
+            vec![],
+            vec![utxo(0, 100_400)],
+            &params(100_000),
+        )
+        .unwrap();
+
+        assert_eq!(result.change, None);
+        assert_eq!(result.fee, 400);
+    }
+
+    #[test]
+    fn replacement_pays_replaced_fee_and_own_relay() {
+        let mut params = params(50_000);
+        params.replaced_fee = Some(5_000);
+
+        let result = select_coins(
+            CoinSelectionAlgorithm::LargestFirst,
+            vec![],
+            vec![utxo(0, 100_000)],
+            &params,
+        )
+        .unwrap();
+
+        // base, input and change weigh 596 WU, 149 sats at the minimum relay rate
+        assert_eq!(result.fee, 5_149);
+        assert_eq!(result.change, Some(100_000 - 50_000 - 5_149));
+    }
+
+    #[test]
+    fn required_utxos_are_always_selected() {
+        for algorithm in [
+            CoinSelectionAlgorithm::BranchAndBound,
+            CoinSelectionAlgorithm::LargestFirst,
+            CoinSelectionAlgorithm::SingleRandomDraw,
+        ] {
+            let result = select_coins(
+                algorithm,
+                vec![utxo(0, 5_000)],
+                vec![utxo(1, 200_000), utxo(2, 300_000)],
+                &params(50_000),
+            )
+            .unwrap();
+
+            assert!(vouts(&result).contains(&0));
+            assert_eq!(
+                result.selected_amount(),
+                50_000 + result.fee + result.change.unwrap_or(0)
+            );
+        }
+    }
+}
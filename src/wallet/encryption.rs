@@ -0,0 +1,159 @@
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng},
+    Key, XChaCha20Poly1305, XNonce,
+};
+
+use super::error::WalletError;
+
+/// Marks a wallet file whose records are encrypted.
+const MAGIC: [u8; 4] = *b"BDKE";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const TAG_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+const VERIFIER_LEN: usize = NONCE_LEN + TAG_LEN;
+
+/// Magic, the three Argon2 cost parameters, the salt and an encrypted empty record
+/// that checks the passphrase before any wallet data is decrypted.
+pub const HEADER_LEN: usize = MAGIC.len() + 3 * 4 + SALT_LEN + VERIFIER_LEN;
+
+/// Argon2id parameters that turn a passphrase into the record key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+    pub salt: [u8; SALT_LEN],
+}
+
+impl KdfParams {
+    /// The Argon2 crate's recommended costs with a fresh random salt.
+    pub fn generate() -> Self {
+        let mut salt = [0; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+
+        Self {
+            memory_kib: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+            salt,
+        }
+    }
+
+    fn parse(header: &[u8]) -> Result<Self, WalletError> {
+        if header.len() < HEADER_LEN || !is_encrypted(header) {
+            return Err(WalletError::InvalidEncryptionHeader);
+        }
+
+        let read_u32 = |offset: usize| {
+            u32::from_le_bytes([
+                header[offset],
+                header[offset + 1],
+                header[offset + 2],
+                header[offset + 3],
+            ])
+        };
+
+        let salt_start = MAGIC.len() + 3 * 4;
+        let mut salt = [0; SALT_LEN];
+        salt.copy_from_slice(&header[salt_start..salt_start + SALT_LEN]);
+
+        Ok(Self {
+            memory_kib: read_u32(MAGIC.len()),
+            iterations: read_u32(MAGIC.len() + 4),
+            parallelism: read_u32(MAGIC.len() + 8),
+            salt,
+        })
+    }
+}
+
+/// Encrypts wallet file records with XChaCha20-Poly1305 under a key derived from a
+/// passphrase. Every record gets its own random nonce, stored in front of it.
+#[derive(Clone)]
+pub struct RecordCipher {
+    params: KdfParams,
+    cipher: XChaCha20Poly1305,
+}
+
+impl RecordCipher {
+    pub fn new(passphrase: &str, params: KdfParams) -> Result<Self, WalletError> {
+        let argon2 = Argon2::new(
+            Algorithm::Argon2id,
+            Version::V0x13,
+            Params::new(
+                params.memory_kib,
+                params.iterations,
+                params.parallelism,
+                Some(KEY_LEN),
+            )?,
+        );
+
+        let mut key = [0; KEY_LEN];
+        argon2.hash_password_into(passphrase.as_bytes(), &params.salt, &mut key)?;
+
+        Ok(Self {
+            params,
+            cipher: XChaCha20Poly1305::new(Key::from_slice(&key)),
+        })
+    }
+
+    /// Derives the key with the parameters in `header` and checks the passphrase against it.
+    pub fn from_header(passphrase: &str, header: &[u8]) -> Result<Self, WalletError> {
+        let cipher = Self::new(passphrase, KdfParams::parse(header)?)?;
+
+        cipher
+            .decrypt(&header[HEADER_LEN - VERIFIER_LEN..HEADER_LEN])
+            .map_err(|_| WalletError::WrongPassphrase)?;
+
+        Ok(cipher)
+    }
+
+    pub fn params(&self) -> KdfParams {
+        self.params
+    }
+
+    pub fn header(&self) -> Result<Vec<u8>, WalletError> {
+        let mut header = Vec::with_capacity(HEADER_LEN);
+
+        header.extend_from_slice(&MAGIC);
+        header.extend_from_slice(&self.params.memory_kib.to_le_bytes());
+        header.extend_from_slice(&self.params.iterations.to_le_bytes());
+        header.extend_from_slice(&self.params.parallelism.to_le_bytes());
+        header.extend_from_slice(&self.params.salt);
+        header.extend_from_slice(&self.encrypt(&[])?);
+
+        Ok(header)
+    }
+
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, WalletError> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| WalletError::Encryption)?;
+
+        let mut record = nonce.to_vec();
+        record.extend_from_slice(&ciphertext);
+
+        Ok(record)
+    }
+
+    pub fn decrypt(&self, record: &[u8]) -> Result<Vec<u8>, WalletError> {
+        if record.len() < NONCE_LEN {
+            return Err(WalletError::Encryption);
+        }
+
+        let (nonce, ciphertext) = record.split_at(NONCE_LEN);
+
+        self.cipher
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| WalletError::Encryption)
+    }
+}
+
+/// Whether `bytes` start like an encrypted wallet file.
+pub fn is_encrypted(bytes: &[u8]) -> bool {
+    bytes.starts_with(&MAGIC)
+}
@@ -1,9 +1,14 @@
 use std::convert::Infallible;
 
 use bdk::{descriptor::DescriptorError, miniscript::psbt::UtxoUpdateError, signer::SignerError};
-use bdk_chain::miniscript::Error;
+use bdk_chain::{
+    local_chain::{AlterCheckPointError, MissingGenesisError},
+    miniscript::Error,
+};
 use bitcoin::psbt::PartiallySignedTransaction;
 
+use super::{keychain_kind::KeychainKind, labels::LabelType};
+
 #[derive(Debug)]
 pub enum WalletError {
     File(std::io::Error),
@@ -11,6 +16,7 @@ pub enum WalletError {
     BIP39(bip39::Error),
     BIP32(bitcoin::bip32::Error),
     Rpc(bitcoind::bitcoincore_rpc::Error),
+    Bitcoind(bitcoind::Error),
     Descriptor(DescriptorError),
     HardenedKey,
     Multipath,
@@ -21,13 +27,86 @@ pub enum WalletError {
     Psbt(bitcoin::psbt::Error),
     Signer(SignerError),
     PsbtFinalise(
-        (
+        Box<(
             PartiallySignedTransaction,
             Vec<bdk::miniscript::psbt::Error>,
-        ),
+        )>,
     ),
     DescriptorConversion(bdk::miniscript::descriptor::ConversionError),
     UtxoUpdate(UtxoUpdateError),
+    MissingGenesis(MissingGenesisError),
+    AlterCheckPoint(AlterCheckPointError),
+    CorruptRecord(usize),
+    PassphraseRequired,
+    WrongPassphrase,
+    InvalidEncryptionHeader,
+    Encryption,
+    Argon2(argon2::Error),
+    InvalidFileHeader,
+    UnsupportedFormatVersion(u16),
+    Sqlite(rusqlite::Error),
+    Json(serde_json::Error),
+    ConsensusEncode(bitcoin::consensus::encode::Error),
+    EncryptionUnsupported,
+    NetworkMismatch {
+        stored: bitcoin::Network,
+        network: bitcoin::Network,
+    },
+    DescriptorMismatch(KeychainKind),
+    MissingKeychain(KeychainKind),
+    NoRecipients,
+    InvalidFeeRate(f32),
+    UnknownUtxo(bitcoin::OutPoint),
+    InsufficientFunds {
+        needed: u64,
+        available: u64,
+    },
+    Broadcast(BroadcastError),
+    UnknownTransaction(bitcoin::Txid),
+    TransactionConfirmed(bitcoin::Txid),
+    IncompleteSignature,
+    NoWalletOutput(bitcoin::Txid),
+    Bip125(Bip125Error),
+    OutPoint(bitcoin::blockdata::transaction::ParseOutPointError),
+    InvalidLabelReference {
+        label_type: LabelType,
+        reference: String,
+    },
+}
+
+/// Why bitcoind refused a transaction passed to `WalletStore::broadcast`.
+#[derive(Debug)]
+pub enum BroadcastError {
+    /// An input was spent already or never existed.
+    MissingInputs,
+    AlreadyInChain,
+    /// Below the node's minimum relay or mempool fee, or too low to replace a conflict.
+    FeeTooLow(String),
+    /// Spends the same coins as a mempool transaction that cannot be replaced.
+    MempoolConflict,
+    /// The locktime or a relative locktime has not passed yet.
+    NonFinal,
+    Dust,
+    Rejected {
+        code: i32,
+        reason: String,
+    },
+}
+
+/// Why a replacement built by `WalletStore::bump_fee` would not be accepted under BIP125.
+#[derive(Debug)]
+pub enum Bip125Error {
+    /// The original does not signal replaceability.
+    NotReplaceable,
+    /// Does not pay the original's fee plus the relay fee for itself.
+    InsufficientFee {
+        needed: u64,
+        paid: u64,
+    },
+    FeeRateNotHigher,
+    /// Would evict more transactions than bitcoind allows, the original and its
+    /// unconfirmed descendants together.
+    TooManyReplacements(usize),
 }
 
 impl From<std::io::Error> for WalletError {
@@ -42,6 +121,12 @@ impl From<bitcoind::bitcoincore_rpc::Error> for WalletError {
     }
 }
 
+impl From<bitcoind::Error> for WalletError {
+    fn from(value: bitcoind::Error) -> Self {
+        Self::Bitcoind(value)
+    }
+}
+
 impl From<bitcoin::bip32::Error> for WalletError {
     fn from(value: bitcoin::bip32::Error) -> Self {
         Self::BIP32(value)
@@ -114,7 +199,7 @@ impl
             Vec<bdk::miniscript::psbt::Error>,
         ),
     ) -> Self {
-        Self::PsbtFinalise((value.0, value.1))
+        Self::PsbtFinalise(Box::new(value))
     }
 }
 
@@ -129,3 +214,57 @@ impl From<UtxoUpdateError> for WalletError {
         Self::UtxoUpdate(value)
     }
 }
+
+impl From<MissingGenesisError> for WalletError {
+    fn from(value: MissingGenesisError) -> Self {
+        Self::MissingGenesis(value)
+    }
+}
+
+impl From<AlterCheckPointError> for WalletError {
+    fn from(value: AlterCheckPointError) -> Self {
+        Self::AlterCheckPoint(value)
+    }
+}
+
+impl From<argon2::Error> for WalletError {
+    fn from(value: argon2::Error) -> Self {
+        Self::Argon2(value)
+    }
+}
+
+impl From<rusqlite::Error> for WalletError {
+    fn from(value: rusqlite::Error) -> Self {
+        Self::Sqlite(value)
+    }
+}
+
+impl From<serde_json::Error> for WalletError {
+    fn from(value: serde_json::Error) -> Self {
+        Self::Json(value)
+    }
+}
+
+impl From<bitcoin::consensus::encode::Error> for WalletError {
+    fn from(value: bitcoin::consensus::encode::Error) -> Self {
+        Self::ConsensusEncode(value)
+    }
+}
+
+impl From<BroadcastError> for WalletError {
+    fn from(value: BroadcastError) -> Self {
+        Self::Broadcast(value)
+    }
+}
+
+impl From<Bip125Error> for WalletError {
+    fn from(value: Bip125Error) -> Self {
+        Self::Bip125(value)
+    }
+}
+
+impl From<bitcoin::blockdata::transaction::ParseOutPointError> for WalletError {
+    fn from(value: bitcoin::blockdata::transaction::ParseOutPointError) -> Self {
+        Self::OutPoint(value)
+    }
+}
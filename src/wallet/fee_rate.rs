@@ -0,0 +1,66 @@
+use super::{error::WalletError, store::Oracle};
+
+/// In sat/vB, the minimum bitcoind relays.
+pub const MIN_RELAY_FEE_RATE: f32 = 1.0;
+
+/// In sat/vB, used when bitcoind has no estimate yet, e.g. on regtest. Same as bitcoind's
+/// `-fallbackfee` default.
+const FALLBACK_FEE_RATE: f32 = 20.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FeeRate {
+    SatPerVb(f32),
+    /// A fixed fee in satoshis, whatever the size of the transaction.
+    Absolute(u64),
+    /// Confirm within this many blocks, according to bitcoind's `estimatesmartfee`.
+    ConfirmationTarget(u16),
+}
+
+impl FeeRate {
+    /// Splits into the sat/vB rate and the fixed fee that coin selection takes, asking
+    /// bitcoind for an estimate if needed. A sat/vB rate below what bitcoind relays, or
+    /// not a number at all, is an error.
+    pub fn resolve(&self, oracle: &Oracle) -> Result<(f32, Option<u64>), WalletError> {
+        match *self {
+            // NaN fails the comparison too
+            FeeRate::SatPerVb(rate) if !(MIN_RELAY_FEE_RATE..=f32::MAX).contains(&rate) => {
+                Err(WalletError::InvalidFeeRate(rate))
+            }
+            FeeRate::SatPerVb(rate) => Ok((rate, None)),
+            FeeRate::Absolute(fee) => Ok((0.0, Some(fee))),
+            FeeRate::ConfirmationTarget(target) => {
+                let rate = oracle
+                    .estimate_fee_rate(target)?
+                    .unwrap_or(FALLBACK_FEE_RATE)
+                    .max(MIN_RELAY_FEE_RATE);
+
+                Ok((rate, None))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoind::bitcoincore_rpc::{Auth, Client};
+
+    use super::*;
+
+    #[test]
+    fn rejects_rates_below_min_relay() {
+        // sat/vB rates are not checked against the node
+        let oracle = Oracle::new(Client::new("http://localhost:1", Auth::None).unwrap());
+
+        for rate in [0.5, 0.0, -1.0, f32::NAN, f32::INFINITY] {
+            assert!(matches!(
+                FeeRate::SatPerVb(rate).resolve(&oracle),
+                Err(WalletError::InvalidFeeRate(_))
+            ));
+        }
+
+        assert!(matches!(
+            FeeRate::SatPerVb(MIN_RELAY_FEE_RATE).resolve(&oracle),
+            Ok((rate, None)) if rate == MIN_RELAY_FEE_RATE
+        ));
+    }
+}
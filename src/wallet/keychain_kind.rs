@@ -7,3 +7,14 @@ pub enum KeychainKind {
     IncomingSwapCoins(String),
     OutgoingSwapCoins(String),
 }
+
+impl KeychainKind {
+    /// Number of unrevealed script pubkeys watched for payments unless configured
+    /// otherwise. Swapcoin keychains are only ever handed to a single counterparty.
+    pub fn default_lookahead(&self) -> u32 {
+        match self {
+            KeychainKind::External | KeychainKind::Internal => 20,
+            KeychainKind::IncomingSwapCoins(_) | KeychainKind::OutgoingSwapCoins(_) => 5,
+        }
+    }
+}
@@ -0,0 +1,597 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use bdk_chain::{Append, PersistBackend};
+use bitcoin::hashes::{sha256, Hash};
+
+use super::{
+    encryption::{self, KdfParams, RecordCipher},
+    error::WalletError,
+    migration::{self, CURRENT_VERSION},
+    store::Changeset,
+};
+
+/// Every record starts with the little-endian length of its payload, a checksum of
+/// the length and a checksum of the payload. A length that does not match its
+/// checksum is damage, not a torn append, whatever follows it.
+const LENGTH_LEN: usize = 4;
+const CHECKSUM_LEN: usize = 4;
+const RECORD_HEADER_LEN: usize = LENGTH_LEN + 2 * CHECKSUM_LEN;
+
+/// Records written before format version 3 have no checksum of the length.
+const LEGACY_RECORD_HEADER_LEN: usize = LENGTH_LEN + CHECKSUM_LEN;
+const LENGTH_CHECKSUM_VERSION: u16 = 3;
+
+/// Stores the wallet as an append-only log of CBOR changesets. Loading folds every
+/// record into one changeset.
+///
+/// Appends are fsynced before `write_changes` returns and whole-file rewrites go
+/// through a temporary file that is renamed over the log, so a crash can at most
+/// leave a torn record at the end of the log. Loading drops such a record.
+///
+/// The file starts with a magic and format version, see `migration`. Files written
+/// in an older format are migrated to the current one when loaded. With a
+/// passphrase, an encryption header follows and every record payload is encrypted,
+/// see `RecordCipher`.
+#[derive(Clone)]
+pub struct KeychainStore {
+    pub file_name: PathBuf,
+    path: PathBuf,
+    cipher: Option<RecordCipher>,
+    recovery: Option<RecoveryReport>,
+}
+
+/// What loading salvaged from a log whose last record was torn by a crash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecoveryReport {
+    pub salvaged_records: usize,
+    pub discarded_bytes: usize,
+}
+
+struct ParsedLog {
+    records: Vec<Changeset>,
+    // length of the prefix made of complete, intact records
+    valid_len: usize,
+}
+
+impl KeychainStore {
+    pub fn new(file_name: PathBuf, path: PathBuf) -> Self {
+        Self {
+            file_name,
+            path,
+            cipher: None,
+            recovery: None,
+        }
+    }
+
+    /// Opens an encrypted wallet file, failing with `WalletError::WrongPassphrase` if
+    /// the passphrase does not match it. A missing file is created encrypted and a
+    /// plaintext one is encrypted the first time it is loaded.
+    pub fn with_passphrase(
+        file_name: PathBuf,
+        path: PathBuf,
+        passphrase: &str,
+    ) -> Result<Self, WalletError> {
+        let mut store = Self::new(file_name, path);
+
+        let cipher = match store.read_encryption_header()? {
+            Some(header) => RecordCipher::from_header(passphrase, &header)?,
+            None => RecordCipher::new(passphrase, KdfParams::generate())?,
+        };
+
+        store.cipher = Some(cipher);
+
+        Ok(store)
+    }
+
+    fn wallet_path(&self) -> PathBuf {
+        self.path.join(&self.file_name)
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    /// Set when the last load had to discard a torn trailing record.
+    pub fn recovery(&self) -> Option<RecoveryReport> {
+        self.recovery
+    }
+
+    /// Rewrites the log as a single record holding the aggregate of all its changesets.
+    /// Returns the number of records that were folded together.
+    pub fn compact(&mut self) -> Result<usize, WalletError> {
+        let records = self.read_records()?;
+        let record_count = records.len();
+
+        self.rewrite(aggregate(records).as_ref())?;
+
+        Ok(record_count)
+    }
+
+    /// Re-encrypts the whole log under a key derived from `new_passphrase` with a fresh
+    /// salt, or stores it in plaintext if `None`. `old_passphrase` must match the
+    /// current one, or be `None` for a plaintext log.
+    pub fn change_passphrase(
+        &mut self,
+        old_passphrase: Option<&str>,
+        new_passphrase: Option<&str>,
+    ) -> Result<(), WalletError> {
+        self.check_passphrase(old_passphrase)?;
+
+        let changeset = aggregate(self.read_records()?);
+
+        self.cipher = match new_passphrase {
+            Some(new_passphrase) => Some(RecordCipher::new(new_passphrase, KdfParams::generate())?),
+            None => None,
+        };
+
+        self.rewrite(changeset.as_ref())
+    }
+
+    /// Checks `passphrase` is the one the log is encrypted under, or `None` for a
+    /// plaintext log.
+    pub fn check_passphrase(&self, passphrase: Option<&str>) -> Result<(), WalletError> {
+        match (self.read_encryption_header()?, passphrase) {
+            (Some(header), Some(passphrase)) => {
+                RecordCipher::from_header(passphrase, &header)?;
+            }
+            (None, None) => {}
+            _ => return Err(WalletError::WrongPassphrase),
+        }
+
+        Ok(())
+    }
+
+    fn read_encryption_header(&self) -> Result<Option<Vec<u8>>, WalletError> {
+        let path = self.wallet_path();
+
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let mut headers = vec![];
+        OpenOptions::new()
+            .read(true)
+            .open(&path)?
+            .take((migration::FILE_HEADER_LEN + encryption::HEADER_LEN) as u64)
+            .read_to_end(&mut headers)?;
+
+        let (_, header) = migration::split_file_header(&headers)?;
+
+        if !encryption::is_encrypted(header) {
+            return Ok(None);
+        }
+
+        Ok(Some(header.to_vec()))
+    }
+
+    /// Atomically replaces the log with one holding only `changeset`.
+    fn rewrite(&self, changeset: Option<&Changeset>) -> Result<(), WalletError> {
+        let path = self.wallet_path();
+        let temp_path = path.with_extension("tmp");
+
+        let temp_file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temp_path)?;
+
+        let mut writer = BufWriter::new(temp_file);
+        writer.write_all(&migration::file_header())?;
+
+        if let Some(cipher) = &self.cipher {
+            writer.write_all(&cipher.header()?)?;
+        }
+
+        if let Some(changeset) = changeset {
+            write_record(&mut writer, changeset, self.cipher.as_ref())?;
+        }
+
+        writer
+            .into_inner()
+            .map_err(|err| err.into_error())?
+            .sync_all()?;
+
+        std::fs::rename(&temp_path, &path)?;
+        sync_parent_dir(&path)?;
+
+        Ok(())
+    }
+
+    fn read_records(&mut self) -> Result<Vec<Changeset>, WalletError> {
+        let path = self.wallet_path();
+
+        if !path.exists() {
+            return Ok(vec![]);
+        }
+
+        let mut bytes = vec![];
+        OpenOptions::new()
+            .read(true)
+            .open(&path)?
+            .read_to_end(&mut bytes)?;
+
+        let (version, after_file_header) = migration::split_file_header(&bytes)?;
+        let encrypted = encryption::is_encrypted(after_file_header);
+
+        let cipher = match (encrypted, &self.cipher) {
+            (true, Some(cipher)) => Some(cipher),
+            (true, None) => return Err(WalletError::PassphraseRequired),
+            (false, _) => None,
+        };
+
+        let mut body_start = bytes.len() - after_file_header.len();
+        if encrypted {
+            body_start += encryption::HEADER_LEN.min(after_file_header.len());
+        }
+
+        let body = &bytes[body_start..];
+        let log = parse_records(body, version, cipher)?;
+
+        let (records, version) = if log.valid_len == body.len() {
+            (log.records, version)
+        } else if let Some(changeset) = legacy_changeset(version, &log, body) {
+            (vec![changeset], 0)
+        } else if log.records.is_empty() {
+            // a new file is written atomically, so nothing intact at all is not a torn append
+            return Err(WalletError::CorruptRecord(0));
+        } else {
+            // cut the torn record off, so that the next append lands after intact data
+            let wallet_file = OpenOptions::new().write(true).open(&path)?;
+            wallet_file.set_len((body_start + log.valid_len) as u64)?;
+            wallet_file.sync_all()?;
+
+            let recovery = RecoveryReport {
+                salvaged_records: log.records.len(),
+                discarded_bytes: body.len() - log.valid_len,
+            };
+
+            self.recovery = Some(recovery);
+
+            (log.records, version)
+        };
+
+        // files in an older format, or plaintext ones opened with a passphrase, are
+        // rewritten in the current format
+        if version < CURRENT_VERSION || (!encrypted && self.cipher.is_some()) {
+            let changeset = aggregate(records);
+            self.rewrite(changeset.as_ref())?;
+            return Ok(changeset.into_iter().collect());
+        }
+
+        Ok(records)
+    }
+}
+
+/// Wallets written before the log format hold a single bare changeset.
+fn legacy_changeset(version: u16, log: &ParsedLog, body: &[u8]) -> Option<Changeset> {
+    if version != 1 || !log.records.is_empty() {
+        return None;
+    }
+
+    migration::decode_record(0, body).ok()
+}
+
+/// Parses records until the end of `bytes` or a torn last record. A damaged record
+/// followed by more data is not the result of an interrupted append and is an error.
+fn parse_records(
+    bytes: &[u8],
+    version: u16,
+    cipher: Option<&RecordCipher>,
+) -> Result<ParsedLog, WalletError> {
+    let header_len = if version < LENGTH_CHECKSUM_VERSION {
+        LEGACY_RECORD_HEADER_LEN
+    } else {
+        RECORD_HEADER_LEN
+    };
+
+    let mut records = vec![];
+    let mut offset = 0;
+
+    while bytes.len() - offset >= header_len {
+        let header = &bytes[offset..offset + header_len];
+        let length_bytes = &header[..LENGTH_LEN];
+        let length = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let checksum = &header[header_len - CHECKSUM_LEN..];
+
+        // otherwise a damaged length could pass for a torn record and cut off the
+        // intact ones after it
+        if header_len == RECORD_HEADER_LEN
+            && record_checksum(length_bytes) != header[LENGTH_LEN..LENGTH_LEN + CHECKSUM_LEN]
+        {
+            return Err(WalletError::CorruptRecord(records.len()));
+        }
+
+        let payload_start = offset + header_len;
+        if bytes.len() - payload_start < length {
+            break;
+        }
+
+        let payload = &bytes[payload_start..payload_start + length];
+        if record_checksum(payload) != checksum {
+            if payload_start + length == bytes.len() {
+                break;
+            }
+
+            return Err(WalletError::CorruptRecord(records.len()));
+        }
+
+        let record = match cipher {
+            Some(cipher) => migration::decode_record(version, &cipher.decrypt(payload)?)?,
+            None => migration::decode_record(version, payload)?,
+        };
+
+        records.push(record);
+        offset = payload_start + length;
+    }
+
+    Ok(ParsedLog {
+        records,
+        valid_len: offset,
+    })
+}
+
+fn record_checksum(bytes: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let hash = sha256::Hash::hash(bytes);
+    let mut checksum = [0; CHECKSUM_LEN];
+    checksum.copy_from_slice(&hash.to_byte_array()[..CHECKSUM_LEN]);
+    checksum
+}
+
+fn write_record(
+    writer: &mut impl Write,
+    changeset: &Changeset,
+    cipher: Option<&RecordCipher>,
+) -> Result<(), WalletError> {
+    let mut payload = serde_cbor::to_vec(changeset)?;
+
+    if let Some(cipher) = cipher {
+        payload = cipher.encrypt(&payload)?;
+    }
+
+    let length = (payload.len() as u32).to_le_bytes();
+
+    writer.write_all(&length)?;
+    writer.write_all(&record_checksum(&length))?;
+    writer.write_all(&record_checksum(&payload))?;
+    writer.write_all(&payload)?;
+
+    Ok(())
+}
+
+fn aggregate(records: Vec<Changeset>) -> Option<Changeset> {
+    records.into_iter().reduce(|mut aggregate, changeset| {
+        aggregate.append(changeset);
+        aggregate
+    })
+}
+
+/// Makes a rename inside the directory durable.
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) -> Result<(), WalletError> {
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn sync_parent_dir(_path: &Path) -> Result<(), WalletError> {
+    Ok(())
+}
+
+impl PersistBackend<Changeset> for KeychainStore {
+    type LoadError = WalletError;
+    type WriteError = WalletError;
+
+    fn write_changes(&mut self, changeset: &Changeset) -> Result<(), Self::WriteError> {
+        if changeset.is_empty() {
+            return Ok(());
+        }
+
+        let path = self.wallet_path();
+
+        if let Some(prefix) = path.parent() {
+            std::fs::create_dir_all(prefix)?;
+        }
+
+        // a new file gets its headers and first record in one atomic write
+        if !path.exists() || std::fs::metadata(&path)?.len() == 0 {
+            return self.rewrite(Some(changeset));
+        }
+
+        let wallet_file = OpenOptions::new().append(true).open(&path)?;
+
+        let mut writer = BufWriter::new(wallet_file);
+        write_record(&mut writer, changeset, self.cipher.as_ref())?;
+        writer
+            .into_inner()
+            .map_err(|err| err.into_error())?
+            .sync_all()?;
+
+        Ok(())
+    }
+
+    fn load_from_persistence(&mut self) -> Result<Option<Changeset>, Self::LoadError> {
+        Ok(aggregate(self.read_records()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::BlockHash;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::wallet::keychain_kind::KeychainKind;
+
+    /// A fresh store in its own directory, removed when the `TempDir` is dropped.
+    fn store() -> (KeychainStore, TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+
+        (reopen(&dir), dir)
+    }
+
+    /// Another store on the wallet file in `dir`, nothing loaded yet.
+    fn reopen(dir: &TempDir) -> KeychainStore {
+        KeychainStore::new(PathBuf::from("wallet"), dir.path().to_path_buf())
+    }
+
+    /// Reveals `External` to `index` and adds a checkpoint at the same height.
+    fn changeset(index: u32) -> Changeset {
+        let mut changeset = Changeset::default();
+        changeset
+            .indexed_tx_graph
+            .indexer
+            .0
+            .insert(KeychainKind::External, index);
+        changeset
+            .local_chain
+            .insert(index, Some(BlockHash::hash(&index.to_le_bytes())));
+        changeset
+    }
+
+    /// Writes `indices` as one record each and returns the file length after every write.
+    fn write_log(store: &mut KeychainStore, indices: &[u32]) -> Vec<u64> {
+        let path = store.wallet_path();
+
+        indices
+            .iter()
+            .map(|index| {
+                store.write_changes(&changeset(*index)).unwrap();
+                std::fs::metadata(&path).unwrap().len()
+            })
+            .collect()
+    }
+
+    fn truncate(path: &Path, len: u64) {
+        OpenOptions::new()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_len(len)
+            .unwrap();
+    }
+
+    #[test]
+    fn drops_torn_trailing_record() {
+        let (mut writer, dir) = store();
+        let lengths = write_log(&mut writer, &[3, 5]);
+        let path = writer.wallet_path();
+
+        // cut the second record off halfway through its payload
+        let cut_len = lengths[0] + (lengths[1] - lengths[0]) / 2;
+        truncate(&path, cut_len);
+
+        let mut reader = reopen(&dir);
+        let changeset = reader.load_from_persistence().unwrap().unwrap();
+
+        assert_eq!(
+            changeset
+                .indexed_tx_graph
+                .indexer
+                .0
+                .get(&KeychainKind::External),
+            Some(&3)
+        );
+        assert_eq!(changeset.local_chain.len(), 1);
+        assert_eq!(
+            reader.recovery(),
+            Some(RecoveryReport {
+                salvaged_records: 1,
+                discarded_bytes: (cut_len - lengths[0]) as usize,
+            })
+        );
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), lengths[0]);
+
+        // the next append lands right after the intact record
+        let appended_len = write_log(&mut reader, &[7])[0];
+        assert_eq!(appended_len - lengths[0], lengths[1] - lengths[0]);
+
+        let mut reopened = reopen(&dir);
+        let changeset = reopened.load_from_persistence().unwrap().unwrap();
+
+        assert_eq!(reopened.recovery(), None);
+        assert_eq!(
+            changeset
+                .indexed_tx_graph
+                .indexer
+                .0
+                .get(&KeychainKind::External),
+            Some(&7)
+        );
+        assert_eq!(
+            changeset.local_chain.keys().copied().collect::<Vec<_>>(),
+            vec![3, 7]
+        );
+    }
+
+    #[test]
+    fn drops_torn_record_header() {
+        let (mut writer, dir) = store();
+        let lengths = write_log(&mut writer, &[3, 5]);
+        let path = writer.wallet_path();
+
+        // only part of the length and checksum made it to disk
+        truncate(&path, lengths[0] + 3);
+
+        let mut reader = reopen(&dir);
+        assert!(reader.load_from_persistence().unwrap().is_some());
+        assert_eq!(
+            reader.recovery(),
+            Some(RecoveryReport {
+                salvaged_records: 1,
+                discarded_bytes: 3,
+            })
+        );
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), lengths[0]);
+    }
+
+    #[test]
+    fn rejects_damaged_record_before_the_end() {
+        let (mut writer, dir) = store();
+        write_log(&mut writer, &[3, 5]);
+        let path = writer.wallet_path();
+
+        // flip a payload byte of the first record, which is followed by another one
+        let mut bytes = std::fs::read(&path).unwrap();
+        let first_payload = migration::FILE_HEADER_LEN + RECORD_HEADER_LEN;
+        bytes[first_payload] ^= 0xff;
+        std::fs::write(&path, &bytes).unwrap();
+
+        let mut reader = reopen(&dir);
+
+        assert!(matches!(
+            reader.load_from_persistence(),
+            Err(WalletError::CorruptRecord(0))
+        ));
+        assert_eq!(reader.recovery(), None);
+        // not a torn append, so the file is left alone
+        assert_eq!(std::fs::read(&path).unwrap(), bytes);
+    }
+
+    #[test]
+    fn rejects_damaged_length_before_the_end() {
+        let (mut writer, dir) = store();
+        let lengths = write_log(&mut writer, &[3, 5, 7]);
+        let path = writer.wallet_path();
+
+        // the second record's length now runs past the end of the file, as if torn
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[lengths[0] as usize + 3] ^= 0x01;
+        std::fs::write(&path, &bytes).unwrap();
+
+        let mut reader = reopen(&dir);
+
+        assert!(matches!(
+            reader.load_from_persistence(),
+            Err(WalletError::CorruptRecord(1))
+        ));
+        assert_eq!(reader.recovery(), None);
+        assert_eq!(std::fs::read(&path).unwrap(), bytes);
+    }
+}
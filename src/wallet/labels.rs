@@ -0,0 +1,68 @@
+use std::str::FromStr;
+
+use bitcoin::{
+    bip32::ExtendedPubKey, key::XOnlyPublicKey, Address, Network, OutPoint, PublicKey, Txid,
+};
+use serde::{Deserialize, Serialize};
+
+/// What a BIP329 label refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LabelType {
+    Tx,
+    Addr,
+    Pubkey,
+    Input,
+    Output,
+    Xpub,
+}
+
+impl LabelType {
+    /// Parses `reference` as what this type refers to and returns it in canonical form,
+    /// so the same reference is always stored under the same key. `None` if it does not
+    /// parse, or is an address of another network.
+    pub fn normalize_reference(self, reference: &str, network: Network) -> Option<String> {
+        let normalized = match self {
+            LabelType::Tx => Txid::from_str(reference).ok()?.to_string(),
+            LabelType::Addr => Address::from_str(reference)
+                .ok()?
+                .require_network(network)
+                .ok()?
+                .to_string(),
+            // taproot keys are x-only
+            LabelType::Pubkey => match PublicKey::from_str(reference) {
+                Ok(pubkey) => pubkey.to_string(),
+                Err(_) => XOnlyPublicKey::from_str(reference).ok()?.to_string(),
+            },
+            LabelType::Input | LabelType::Output => OutPoint::from_str(reference).ok()?.to_string(),
+            LabelType::Xpub => ExtendedPubKey::from_str(reference).ok()?.to_string(),
+        };
+
+        Some(normalized)
+    }
+}
+
+/// A label as stored by the wallet, keyed by its type and reference.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Label {
+    pub label: String,
+    /// Key origin of the descriptor the reference belongs to, kept for export.
+    pub origin: Option<String>,
+}
+
+/// A line of a BIP329 JSONL export.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Bip329Record {
+    #[serde(rename = "type")]
+    pub label_type: LabelType,
+    /// Txid, address, pubkey, `txid:vout` or xpub, depending on `label_type`.
+    #[serde(rename = "ref")]
+    pub reference: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<String>,
+    /// Only for outputs, `false` if the output is frozen.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spendable: Option<bool>,
+}
@@ -0,0 +1,246 @@
+use serde_cbor::Value;
+
+use super::{error::WalletError, store::Changeset};
+
+/// Starts every wallet file written since the format was versioned.
+const MAGIC: [u8; 4] = *b"BDKW";
+
+/// Version 0 files are a single bare CBOR changeset. Version 1 files are a log of
+/// checksummed records, behind an encryption header if encrypted. Version 2 puts
+/// the magic and version in front of the version 1 layout. Version 3 adds a checksum
+/// of the length to every record header.
+pub const CURRENT_VERSION: u16 = 3;
+
+pub const FILE_HEADER_LEN: usize = MAGIC.len() + 2;
+
+/// Takes a changeset record written by one format version to the schema of the next.
+type Migration = fn(Value) -> Result<Value, WalletError>;
+
+/// `MIGRATIONS[n]` upgrades a record of version `n` to version `n + 1`. A change to
+/// `Changeset` or `KeychainKind` that old records do not deserialize into bumps
+/// `CURRENT_VERSION` and adds the migration here.
+const MIGRATIONS: [Migration; CURRENT_VERSION as usize] = [
+    // the checkpoint, mempool eviction and birthday fields deserialize to defaults
+    unchanged_schema,
+    // only the file header was added
+    unchanged_schema,
+    // only the record header changed
+    unchanged_schema,
+];
+
+fn unchanged_schema(record: Value) -> Result<Value, WalletError> {
+    Ok(record)
+}
+
+pub fn file_header() -> [u8; FILE_HEADER_LEN] {
+    let mut header = [0; FILE_HEADER_LEN];
+    header[..MAGIC.len()].copy_from_slice(&MAGIC);
+    header[MAGIC.len()..].copy_from_slice(&CURRENT_VERSION.to_le_bytes());
+    header
+}
+
+/// Splits the file header off a wallet file and returns the format version. Files
+/// without a header are version 1, or version 0 if their records do not parse.
+pub fn split_file_header(bytes: &[u8]) -> Result<(u16, &[u8]), WalletError> {
+    if !bytes.starts_with(&MAGIC) {
+        return Ok((1, bytes));
+    }
+
+    if bytes.len() < FILE_HEADER_LEN {
+        return Err(WalletError::InvalidFileHeader);
+    }
+
+    let version = u16::from_le_bytes([bytes[MAGIC.len()], bytes[MAGIC.len() + 1]]);
+
+    if version > CURRENT_VERSION {
+        return Err(WalletError::UnsupportedFormatVersion(version));
+    }
+
+    Ok((version, &bytes[FILE_HEADER_LEN..]))
+}
+
+/// Decodes a changeset record written by format `version`, migrating it to the
+/// current schema.
+pub fn decode_record(version: u16, payload: &[u8]) -> Result<Changeset, WalletError> {
+    let mut record: Value = serde_cbor::from_slice(payload)?;
+
+    for migration in &MIGRATIONS[version as usize..] {
+        record = migration(record)?;
+    }
+
+    Ok(serde_cbor::value::from_value(record)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, str::FromStr};
+
+    use bdk_chain::PersistBackend;
+    use bitcoin::{
+        hashes::{sha256, Hash},
+        BlockHash, Txid,
+    };
+
+    use super::*;
+    use crate::wallet::{keychain_kind::KeychainKind, keychain_store::KeychainStore};
+
+    /// Written by the first release, before the log format. A frozen copy, since the
+    /// wallet `main` opens is migrated and appended to.
+    const V0_WALLET: &[u8] = include_bytes!("../../tests/fixtures/wallet-v0.cbor");
+    const V0_TXID: &str = "117dd7ace4b3d88526b16acde9a952b41e9d21560f1b11199e1bfdfc57e1e5f8";
+
+    /// Loads `bytes` as a wallet file, returning the result and the file afterwards.
+    fn load(bytes: &[u8]) -> (Result<Option<Changeset>, WalletError>, Vec<u8>) {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("wallet"), bytes).unwrap();
+
+        let mut store = KeychainStore::new(PathBuf::from("wallet"), dir.path().to_path_buf());
+        let changeset = store.load_from_persistence();
+        let file = std::fs::read(dir.path().join("wallet")).unwrap();
+
+        (changeset, file)
+    }
+
+    fn checksum(bytes: &[u8]) -> [u8; 4] {
+        let mut checksum = [0; 4];
+        checksum.copy_from_slice(&sha256::Hash::hash(bytes).to_byte_array()[..4]);
+        checksum
+    }
+
+    /// A v1 log record: payload length, checksum, CBOR payload. From v3 on the length
+    /// has its own checksum.
+    fn record(changeset: &Changeset, version: u16) -> Vec<u8> {
+        let payload = serde_cbor::to_vec(changeset).unwrap();
+        let length = (payload.len() as u32).to_le_bytes();
+
+        let mut record = length.to_vec();
+        if version >= 3 {
+            record.extend_from_slice(&checksum(&length));
+        }
+        record.extend_from_slice(&checksum(&payload));
+        record.extend_from_slice(&payload);
+        record
+    }
+
+    /// Two records revealing `External` to 3 and then 5, each with a checkpoint.
+    fn log(version: u16) -> Vec<u8> {
+        let mut first = Changeset::default();
+        first
+            .indexed_tx_graph
+            .indexer
+            .0
+            .insert(KeychainKind::External, 3);
+        first.local_chain.insert(1, Some(BlockHash::hash(&[1])));
+
+        let mut second = Changeset::default();
+        second
+            .indexed_tx_graph
+            .indexer
+            .0
+            .insert(KeychainKind::External, 5);
+        second.local_chain.insert(2, Some(BlockHash::hash(&[2])));
+
+        [record(&first, version), record(&second, version)].concat()
+    }
+
+    fn assert_log_folded(changeset: &Changeset) {
+        assert_eq!(
+            changeset
+                .indexed_tx_graph
+                .indexer
+                .0
+                .get(&KeychainKind::External),
+            Some(&5)
+        );
+        assert_eq!(changeset.local_chain.len(), 2);
+        assert_eq!(
+            changeset.local_chain.get(&2),
+            Some(&Some(BlockHash::hash(&[2])))
+        );
+    }
+
+    /// Checks `file` was rewritten as one record behind the current header.
+    fn assert_migrated(file: &[u8]) {
+        assert!(file.starts_with(&file_header()));
+        assert_eq!(split_file_header(file).unwrap().0, CURRENT_VERSION);
+
+        let length = &file[FILE_HEADER_LEN..FILE_HEADER_LEN + 4];
+        let length = u32::from_le_bytes([length[0], length[1], length[2], length[3]]) as usize;
+        // the length, its checksum and the payload checksum
+        assert_eq!(file.len(), FILE_HEADER_LEN + 12 + length);
+    }
+
+    #[test]
+    fn migrates_v0_bare_changeset() {
+        let (changeset, file) = load(V0_WALLET);
+        let changeset = changeset.unwrap().unwrap();
+
+        let graph = &changeset.indexed_tx_graph.graph;
+        let txid = Txid::from_str(V0_TXID).unwrap();
+        assert_eq!(graph.txs.len(), 1);
+        assert!(graph.txs.iter().any(|tx| tx.txid() == txid));
+        assert_eq!(graph.anchors.len(), 1);
+        assert!(graph
+            .anchors
+            .iter()
+            .all(|(anchor, anchored)| { anchor.confirmation_height == 120 && *anchored == txid }));
+        assert_eq!(
+            changeset
+                .indexed_tx_graph
+                .indexer
+                .0
+                .get(&KeychainKind::External),
+            Some(&0)
+        );
+        assert!(changeset.local_chain.is_empty());
+        assert!(changeset.birthday.is_none());
+
+        assert_migrated(&file);
+    }
+
+    #[test]
+    fn migrates_v1_headerless_log() {
+        let (changeset, file) = load(&log(1));
+
+        assert_log_folded(&changeset.unwrap().unwrap());
+        assert_migrated(&file);
+    }
+
+    #[test]
+    fn migrates_v2_log() {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&2u16.to_le_bytes());
+        bytes.extend_from_slice(&log(2));
+
+        let (changeset, file) = load(&bytes);
+
+        assert_log_folded(&changeset.unwrap().unwrap());
+        assert_migrated(&file);
+    }
+
+    #[test]
+    fn loads_v3_log() {
+        let bytes = [file_header().to_vec(), log(3)].concat();
+        let (changeset, file) = load(&bytes);
+
+        assert_log_folded(&changeset.unwrap().unwrap());
+
+        // already current, left as written
+        assert_eq!(file, bytes);
+    }
+
+    #[test]
+    fn rejects_newer_version() {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&(CURRENT_VERSION + 1).to_le_bytes());
+        bytes.extend_from_slice(&log(CURRENT_VERSION));
+
+        let (changeset, file) = load(&bytes);
+
+        assert!(matches!(
+            changeset,
+            Err(WalletError::UnsupportedFormatVersion(version)) if version == CURRENT_VERSION + 1
+        ));
+        assert_eq!(file, bytes);
+    }
+}
@@ -1,6 +1,16 @@
+pub mod backend;
+pub mod coin_selection;
+pub mod encryption;
 pub mod error;
+pub mod fee_rate;
 pub mod fidelity;
 pub mod keychain_kind;
+pub mod keychain_store;
+pub mod labels;
+pub mod migration;
 pub mod signer;
+pub mod spk_window;
+pub mod sqlite_store;
 pub mod store;
 pub mod swapcoin;
+pub mod tx_builder;
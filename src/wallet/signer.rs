@@ -1,11 +1,10 @@
 use bdk::{
-    descriptor::{self, ExtendedDescriptor, IntoWalletDescriptor},
+    descriptor::{ExtendedDescriptor, IntoWalletDescriptor},
     keys::{DescriptorPublicKey, KeyMap},
     miniscript::{
         descriptor::{DescriptorXKey, Wildcard},
         ForEachKey,
     },
-    Wallet,
 };
 use bitcoin::{bip32::ChildNumber, key::Secp256k1, secp256k1::All, Network};
 
@@ -42,5 +41,5 @@ pub fn into_wallet_descriptor_checked<E: IntoWalletDescriptor>(
 
     descriptor.sanity_check()?;
 
-    return Ok((descriptor, keymap));
+    Ok((descriptor, keymap))
 }
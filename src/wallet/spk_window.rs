@@ -0,0 +1,73 @@
+use std::collections::BTreeMap;
+
+use bdk_chain::keychain::KeychainTxOutIndex;
+use bitcoin::{Script, ScriptBuf, Transaction};
+
+use super::{error::WalletError, keychain_kind::KeychainKind};
+
+/// Script pubkeys derived past the last revealed index of each keychain, so payments
+/// to addresses the index has not revealed yet can still be recognised.
+#[derive(Default)]
+pub struct SpkWindow {
+    gaps: BTreeMap<KeychainKind, u32>,
+    spks: BTreeMap<ScriptBuf, (KeychainKind, u32)>,
+}
+
+impl SpkWindow {
+    /// `gaps` is the number of unrevealed script pubkeys to watch per keychain.
+    pub fn new(gaps: BTreeMap<KeychainKind, u32>) -> Self {
+        Self {
+            gaps,
+            spks: BTreeMap::new(),
+        }
+    }
+
+    pub fn gap(&self, keychain: &KeychainKind) -> u32 {
+        self.gaps.get(keychain).copied().unwrap_or(0)
+    }
+
+    /// Takes effect on the next `refresh`.
+    pub fn set_gap(&mut self, keychain: KeychainKind, gap: u32) {
+        self.gaps.insert(keychain, gap);
+    }
+
+    /// Re-derives the window so that it starts right after each keychain's last revealed index.
+    pub fn refresh(&mut self, index: &KeychainTxOutIndex<KeychainKind>) -> Result<(), WalletError> {
+        self.spks.clear();
+
+        for (keychain, descriptor) in index.keychains() {
+            let start = index
+                .last_revealed_index(keychain)
+                .map_or(0, |last_revealed| last_revealed + 1);
+
+            // a descriptor without a wildcard only has the one script pubkey
+            let mut end = start.saturating_add(self.gap(keychain));
+            if !descriptor.has_wildcard() {
+                end = end.min(1);
+            }
+
+            for derivation_index in start..end {
+                let spk = descriptor
+                    .at_derivation_index(derivation_index)?
+                    .script_pubkey();
+
+                self.spks.insert(spk, (keychain.clone(), derivation_index));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn index_of_spk(&self, spk: &Script) -> Option<&(KeychainKind, u32)> {
+        self.spks.get(spk)
+    }
+
+    /// Keychain indices in the window that `tx` pays to.
+    pub fn matches(&self, tx: &Transaction) -> Vec<(KeychainKind, u32)> {
+        tx.output
+            .iter()
+            .filter_map(|txout| self.index_of_spk(&txout.script_pubkey))
+            .cloned()
+            .collect()
+    }
+}
@@ -0,0 +1,648 @@
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use bdk_chain::{Append, BlockId, ConfirmationTimeHeightAnchor, PersistBackend};
+use bitcoin::{
+    consensus::encode::{deserialize, serialize},
+    BlockHash, OutPoint, ScriptBuf, Transaction, TxOut, Txid,
+};
+use rusqlite::{params, Connection, OptionalExtension};
+
+use super::{
+    error::WalletError,
+    keychain_kind::KeychainKind,
+    labels::{Label, LabelType},
+    store::{Birthday, Changeset},
+};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS transactions (
+        txid TEXT PRIMARY KEY,
+        raw_tx BLOB NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS txouts (
+        txid TEXT NOT NULL,
+        vout INTEGER NOT NULL,
+        value INTEGER NOT NULL,
+        script_pubkey BLOB NOT NULL,
+        PRIMARY KEY (txid, vout)
+    );
+    CREATE TABLE IF NOT EXISTS anchors (
+        txid TEXT NOT NULL,
+        block_height INTEGER NOT NULL,
+        block_hash TEXT NOT NULL,
+        confirmation_height INTEGER NOT NULL,
+        confirmation_time INTEGER NOT NULL,
+        PRIMARY KEY (txid, block_height, block_hash)
+    );
+    CREATE TABLE IF NOT EXISTS last_seen (
+        txid TEXT PRIMARY KEY,
+        seen_at INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS keychain_indices (
+        keychain TEXT PRIMARY KEY,
+        last_revealed INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS blocks (
+        height INTEGER PRIMARY KEY,
+        block_hash TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS mempool_evictions (
+        txid TEXT PRIMARY KEY,
+        evicted_at INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS descriptors (
+        keychain TEXT PRIMARY KEY,
+        descriptor TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS added_descriptors (
+        keychain TEXT PRIMARY KEY,
+        descriptor TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS network (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        network TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS birthday (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        height INTEGER,
+        time INTEGER
+    );
+    CREATE TABLE IF NOT EXISTS frozen (
+        txid TEXT NOT NULL,
+        vout INTEGER NOT NULL,
+        PRIMARY KEY (txid, vout)
+    );
+    CREATE TABLE IF NOT EXISTS labels (
+        type TEXT NOT NULL,
+        ref TEXT NOT NULL,
+        label TEXT NOT NULL,
+        origin TEXT,
+        PRIMARY KEY (type, ref)
+    );
+";
+
+/// Persists the wallet in SQLite, with a table per kind of changeset entry, so that
+/// history can be queried without loading the whole wallet.
+pub struct SqliteStore {
+    path: PathBuf,
+    conn: Connection,
+}
+
+impl SqliteStore {
+    pub fn open(path: &Path) -> Result<Self, WalletError> {
+        if let Some(prefix) = path.parent() {
+            std::fs::create_dir_all(prefix)?;
+        }
+
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            conn,
+        })
+    }
+
+    /// Opens another connection to the same database.
+    pub fn try_clone(&self) -> Result<Self, WalletError> {
+        Self::open(&self.path)
+    }
+
+    /// Looks a single transaction up without loading the rest of the wallet.
+    pub fn get_tx(&self, txid: &Txid) -> Result<Option<Transaction>, WalletError> {
+        let raw_tx = self
+            .conn
+            .query_row(
+                "SELECT raw_tx FROM transactions WHERE txid = ?1",
+                params![txid.to_string()],
+                |row| row.get::<_, Vec<u8>>(0),
+            )
+            .optional()?;
+
+        match raw_tx {
+            Some(raw_tx) => Ok(Some(deserialize(&raw_tx)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Txids confirmed at or above `height`, lowest first.
+    pub fn txids_confirmed_since(&self, height: u32) -> Result<Vec<Txid>, WalletError> {
+        let mut statement = self.conn.prepare(
+            "SELECT txid, MIN(confirmation_height) AS height FROM anchors
+             GROUP BY txid HAVING height >= ?1 ORDER BY height",
+        )?;
+
+        let txids = statement
+            .query_map(params![height], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        txids.iter().map(|txid| Ok(Txid::from_str(txid)?)).collect()
+    }
+}
+
+impl PersistBackend<Changeset> for SqliteStore {
+    type LoadError = WalletError;
+    type WriteError = WalletError;
+
+    fn write_changes(&mut self, changeset: &Changeset) -> Result<(), Self::WriteError> {
+        if changeset.is_empty() {
+            return Ok(());
+        }
+
+        let db_tx = self.conn.transaction()?;
+        let graph = &changeset.indexed_tx_graph.graph;
+
+        for tx in &graph.txs {
+            db_tx.execute(
+                "INSERT OR IGNORE INTO transactions (txid, raw_tx) VALUES (?1, ?2)",
+                params![tx.txid().to_string(), serialize(tx)],
+            )?;
+        }
+
+        for (outpoint, txout) in &graph.txouts {
+            db_tx.execute(
+                "INSERT OR IGNORE INTO txouts (txid, vout, value, script_pubkey)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    outpoint.txid.to_string(),
+                    outpoint.vout,
+                    txout.value as i64,
+                    txout.script_pubkey.as_bytes()
+                ],
+            )?;
+        }
+
+        for (anchor, txid) in &graph.anchors {
+            db_tx.execute(
+                "INSERT OR IGNORE INTO anchors
+                 (txid, block_height, block_hash, confirmation_height, confirmation_time)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    txid.to_string(),
+                    anchor.anchor_block.height,
+                    anchor.anchor_block.hash.to_string(),
+                    anchor.confirmation_height,
+                    anchor.confirmation_time as i64
+                ],
+            )?;
+        }
+
+        for (txid, seen_at) in &graph.last_seen {
+            db_tx.execute(
+                "INSERT INTO last_seen (txid, seen_at) VALUES (?1, ?2)
+                 ON CONFLICT (txid) DO UPDATE SET seen_at = MAX(seen_at, excluded.seen_at)",
+                params![txid.to_string(), *seen_at as i64],
+            )?;
+        }
+
+        for (keychain, last_revealed) in &changeset.indexed_tx_graph.indexer.0 {
+            db_tx.execute(
+                "INSERT INTO keychain_indices (keychain, last_revealed) VALUES (?1, ?2)
+                 ON CONFLICT (keychain) DO UPDATE
+                 SET last_revealed = MAX(last_revealed, excluded.last_revealed)",
+                params![serde_json::to_string(keychain)?, last_revealed],
+            )?;
+        }
+
+        for (height, block_hash) in &changeset.local_chain {
+            match block_hash {
+                Some(block_hash) => db_tx.execute(
+                    "INSERT OR REPLACE INTO blocks (height, block_hash) VALUES (?1, ?2)",
+                    params![height, block_hash.to_string()],
+                )?,
+                None => db_tx.execute("DELETE FROM blocks WHERE height = ?1", params![height])?,
+            };
+        }
+
+        for (txid, evicted_at) in &changeset.mempool_evictions {
+            db_tx.execute(
+                "INSERT OR REPLACE INTO mempool_evictions (txid, evicted_at) VALUES (?1, ?2)",
+                params![txid.to_string(), *evicted_at as i64],
+            )?;
+        }
+
+        if let Some(birthday) = changeset.birthday {
+            let (height, time) = match birthday {
+                Birthday::Height(height) => (Some(height), None),
+                Birthday::Time(time) => (None, Some(time as i64)),
+            };
+
+            db_tx.execute(
+                "INSERT OR REPLACE INTO birthday (id, height, time) VALUES (0, ?1, ?2)",
+                params![height, time],
+            )?;
+        }
+
+        for (keychain, descriptor) in &changeset.descriptors {
+            db_tx.execute(
+                "INSERT OR REPLACE INTO descriptors (keychain, descriptor) VALUES (?1, ?2)",
+                params![serde_json::to_string(keychain)?, descriptor],
+            )?;
+        }
+
+        for (keychain, descriptor) in &changeset.added_descriptors {
+            db_tx.execute(
+                "INSERT OR REPLACE INTO added_descriptors (keychain, descriptor) VALUES (?1, ?2)",
+                params![serde_json::to_string(keychain)?, descriptor],
+            )?;
+        }
+
+        if let Some(network) = changeset.network {
+            db_tx.execute(
+                "INSERT OR REPLACE INTO network (id, network) VALUES (0, ?1)",
+                params![serde_json::to_string(&network)?],
+            )?;
+        }
+
+        for (outpoint, frozen) in &changeset.frozen {
+            match frozen {
+                true => db_tx.execute(
+                    "INSERT OR IGNORE INTO frozen (txid, vout) VALUES (?1, ?2)",
+                    params![outpoint.txid.to_string(), outpoint.vout],
+                )?,
+                false => db_tx.execute(
+                    "DELETE FROM frozen WHERE txid = ?1 AND vout = ?2",
+                    params![outpoint.txid.to_string(), outpoint.vout],
+                )?,
+            };
+        }
+
+        for ((label_type, reference), label) in &changeset.labels {
+            let label_type = serde_json::to_string(label_type)?;
+            match label {
+                Some(label) => db_tx.execute(
+                    "INSERT OR REPLACE INTO labels (type, ref, label, origin)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    params![label_type, reference, label.label, label.origin],
+                )?,
+                None => db_tx.execute(
+                    "DELETE FROM labels WHERE type = ?1 AND ref = ?2",
+                    params![label_type, reference],
+                )?,
+            };
+        }
+
+        db_tx.commit()?;
+
+        Ok(())
+    }
+
+    fn load_from_persistence(&mut self) -> Result<Option<Changeset>, Self::LoadError> {
+        let mut changeset = Changeset::default();
+
+        let mut statement = self.conn.prepare("SELECT raw_tx FROM transactions")?;
+        for raw_tx in statement.query_map([], |row| row.get::<_, Vec<u8>>(0))? {
+            let tx: Transaction = deserialize(&raw_tx?)?;
+            changeset.indexed_tx_graph.graph.txs.insert(tx);
+        }
+
+        let mut statement = self
+            .conn
+            .prepare("SELECT txid, vout, value, script_pubkey FROM txouts")?;
+        let rows = statement.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, u32>(1)?,
+                row.get::<_, i64>(2)?,
+                row.get::<_, Vec<u8>>(3)?,
+            ))
+        })?;
+        for row in rows {
+            let (txid, vout, value, script_pubkey) = row?;
+            changeset.indexed_tx_graph.graph.txouts.insert(
+                OutPoint::new(Txid::from_str(&txid)?, vout),
+                TxOut {
+                    value: value as u64,
+                    script_pubkey: ScriptBuf::from_bytes(script_pubkey),
+                },
+            );
+        }
+
+        let mut statement = self.conn.prepare(
+            "SELECT txid, block_height, block_hash, confirmation_height, confirmation_time
+             FROM anchors",
+        )?;
+        let rows = statement.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, u32>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, u32>(3)?,
+                row.get::<_, i64>(4)?,
+            ))
+        })?;
+        for row in rows {
+            let (txid, block_height, block_hash, confirmation_height, confirmation_time) = row?;
+            let anchor = ConfirmationTimeHeightAnchor {
+                anchor_block: BlockId {
+                    height: block_height,
+                    hash: BlockHash::from_str(&block_hash)?,
+                },
+                confirmation_height,
+                confirmation_time: confirmation_time as u64,
+            };
+            changeset
+                .indexed_tx_graph
+                .graph
+                .anchors
+                .insert((anchor, Txid::from_str(&txid)?));
+        }
+
+        let mut statement = self.conn.prepare("SELECT txid, seen_at FROM last_seen")?;
+        let rows = statement.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+        })?;
+        for row in rows {
+            let (txid, seen_at) = row?;
+            changeset
+                .indexed_tx_graph
+                .graph
+                .last_seen
+                .insert(Txid::from_str(&txid)?, seen_at as u64);
+        }
+
+        let mut statement = self
+            .conn
+            .prepare("SELECT keychain, last_revealed FROM keychain_indices")?;
+        let rows = statement.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, u32>(1)?))
+        })?;
+        for row in rows {
+            let (keychain, last_revealed) = row?;
+            let keychain: KeychainKind = serde_json::from_str(&keychain)?;
+            changeset
+                .indexed_tx_graph
+                .indexer
+                .0
+                .insert(keychain, last_revealed);
+        }
+
+        let mut statement = self.conn.prepare("SELECT height, block_hash FROM blocks")?;
+        let rows = statement.query_map([], |row| {
+            Ok((row.get::<_, u32>(0)?, row.get::<_, String>(1)?))
+        })?;
+        for row in rows {
+            let (height, block_hash) = row?;
+            changeset
+                .local_chain
+                .insert(height, Some(BlockHash::from_str(&block_hash)?));
+        }
+
+        let mut statement = self
+            .conn
+            .prepare("SELECT txid, evicted_at FROM mempool_evictions")?;
+        let rows = statement.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+        })?;
+        for row in rows {
+            let (txid, evicted_at) = row?;
+            changeset
+                .mempool_evictions
+                .insert(Txid::from_str(&txid)?, evicted_at as u64);
+        }
+
+        changeset.birthday = self
+            .conn
+            .query_row("SELECT height, time FROM birthday", [], |row| {
+                Ok((row.get::<_, Option<u32>>(0)?, row.get::<_, Option<i64>>(1)?))
+            })
+            .optional()?
+            .and_then(|birthday| match birthday {
+                (Some(height), _) => Some(Birthday::Height(height)),
+                (None, Some(time)) => Some(Birthday::Time(time as u64)),
+                (None, None) => None,
+            });
+
+        let mut statement = self
+            .conn
+            .prepare("SELECT keychain, descriptor FROM descriptors")?;
+        let rows = statement.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+        for row in rows {
+            let (keychain, descriptor) = row?;
+            let keychain: KeychainKind = serde_json::from_str(&keychain)?;
+            changeset.descriptors.insert(keychain, descriptor);
+        }
+
+        let mut statement = self
+            .conn
+            .prepare("SELECT keychain, descriptor FROM added_descriptors")?;
+        let rows = statement.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+        for row in rows {
+            let (keychain, descriptor) = row?;
+            let keychain: KeychainKind = serde_json::from_str(&keychain)?;
+            changeset.added_descriptors.insert(keychain, descriptor);
+        }
+
+        let network = self
+            .conn
+            .query_row("SELECT network FROM network", [], |row| {
+                row.get::<_, String>(0)
+            })
+            .optional()?;
+        changeset.network = network
+            .map(|network| serde_json::from_str(&network))
+            .transpose()?;
+
+        let mut statement = self.conn.prepare("SELECT txid, vout FROM frozen")?;
+        let rows = statement.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, u32>(1)?))
+        })?;
+        for row in rows {
+            let (txid, vout) = row?;
+            changeset
+                .frozen
+                .insert(OutPoint::new(Txid::from_str(&txid)?, vout), true);
+        }
+
+        let mut statement = self
+            .conn
+            .prepare("SELECT type, ref, label, origin FROM labels")?;
+        let rows = statement.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, Option<String>>(3)?,
+            ))
+        })?;
+        for row in rows {
+            let (label_type, reference, label, origin) = row?;
+            let label_type: LabelType = serde_json::from_str(&label_type)?;
+            changeset
+                .labels
+                .insert((label_type, reference), Some(Label { label, origin }));
+        }
+
+        if changeset.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(changeset))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bdk_chain::{local_chain, tx_graph};
+    use bitcoin::{absolute::LockTime, hashes::Hash, Network, TxIn};
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    /// Opens a database in its own directory, removed when the `TempDir` is dropped.
+    fn open() -> (SqliteStore, TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+
+        (
+            SqliteStore::open(&dir.path().join("wallet.sqlite")).unwrap(),
+            dir,
+        )
+    }
+
+    fn tx() -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::hash(&[1]), 0),
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: 50_000,
+                script_pubkey: ScriptBuf::from_bytes(vec![0x00, 0x14, 0xab]),
+            }],
+        }
+    }
+
+    /// Fills every field, with rows that `tombstones` removes again.
+    fn changeset() -> Changeset {
+        let tx = tx();
+        let txid = tx.txid();
+        let swapcoin = KeychainKind::IncomingSwapCoins("swap".to_string());
+
+        let mut graph = tx_graph::ChangeSet::<ConfirmationTimeHeightAnchor>::default();
+        graph.txs.insert(tx);
+        graph.txouts.insert(
+            OutPoint::new(Txid::hash(&[2]), 1),
+            TxOut {
+                value: 1_000,
+                script_pubkey: ScriptBuf::from_bytes(vec![0x51]),
+            },
+        );
+        graph.anchors.insert((
+            ConfirmationTimeHeightAnchor {
+                anchor_block: BlockId {
+                    height: 102,
+                    hash: BlockHash::hash(&[102]),
+                },
+                confirmation_height: 101,
+                confirmation_time: 1_700_000_000,
+            },
+            txid,
+        ));
+        graph.last_seen.insert(txid, 1_700_000_100);
+
+        let mut changeset = Changeset::default();
+        changeset.indexed_tx_graph.graph = graph;
+        changeset
+            .indexed_tx_graph
+            .indexer
+            .0
+            .insert(KeychainKind::External, 7);
+        changeset
+            .indexed_tx_graph
+            .indexer
+            .0
+            .insert(swapcoin.clone(), 2);
+        changeset.local_chain = local_chain::ChangeSet::from([
+            (0, Some(BlockHash::hash(&[0]))),
+            (101, Some(BlockHash::hash(&[101]))),
+            (102, Some(BlockHash::hash(&[102]))),
+        ]);
+        changeset
+            .mempool_evictions
+            .insert(Txid::hash(&[3]), 1_700_000_200);
+        changeset.birthday = Some(Birthday::Time(1_690_000_000));
+        changeset
+            .descriptors
+            .insert(KeychainKind::External, "wpkh(external)".to_string());
+        changeset
+            .added_descriptors
+            .insert(swapcoin, "wpkh(swapcoin)".to_string());
+        changeset.network = Some(Network::Regtest);
+        changeset.frozen.insert(OutPoint::new(txid, 0), true);
+        changeset
+            .frozen
+            .insert(OutPoint::new(Txid::hash(&[2]), 1), true);
+        changeset.labels.insert(
+            (LabelType::Tx, txid.to_string()),
+            Some(Label {
+                label: "rent".to_string(),
+                origin: Some("wpkh([d34db33f/84'/1'/0'])".to_string()),
+            }),
+        );
+        changeset.labels.insert(
+            (LabelType::Output, format!("{}:0", txid)),
+            Some(Label {
+                label: "change".to_string(),
+                origin: None,
+            }),
+        );
+        changeset
+    }
+
+    /// Disconnects the tip, unfreezes an outpoint and removes a label.
+    fn tombstones() -> Changeset {
+        let txid = tx().txid();
+
+        let mut changeset = Changeset::default();
+        changeset.local_chain.insert(102, None);
+        changeset
+            .frozen
+            .insert(OutPoint::new(Txid::hash(&[2]), 1), false);
+        changeset
+            .labels
+            .insert((LabelType::Output, format!("{}:0", txid)), None);
+        changeset
+    }
+
+    #[test]
+    fn round_trips_changeset() {
+        let (mut store, dir) = open();
+
+        store.write_changes(&changeset()).unwrap();
+        store.write_changes(&tombstones()).unwrap();
+
+        // a fresh connection, so nothing is served from memory
+        let loaded = SqliteStore::open(&dir.path().join("wallet.sqlite"))
+            .unwrap()
+            .load_from_persistence()
+            .unwrap()
+            .unwrap();
+
+        let mut expected = changeset();
+        expected.append(tombstones());
+        expected
+            .local_chain
+            .retain(|_, block_hash| block_hash.is_some());
+        expected.frozen.retain(|_, frozen| *frozen);
+        expected.labels.retain(|_, label| label.is_some());
+
+        assert_eq!(loaded, expected);
+    }
+
+    #[test]
+    fn loads_empty_database_as_none() {
+        let (mut store, _dir) = open();
+
+        assert!(store.load_from_persistence().unwrap().is_none());
+    }
+}
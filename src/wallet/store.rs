@@ -4,32 +4,229 @@ use bdk::{
 };
 use bdk_chain::{
     indexed_tx_graph,
-    keychain::{self, KeychainTxOutIndex},
-    Append, BlockId, ChainOracle, ConfirmationTimeHeightAnchor, IndexedTxGraph, Persist,
-    PersistBackend,
+    keychain::{self, Balance, KeychainTxOutIndex},
+    local_chain::{self, LocalChain},
+    Append, BlockId, ChainOracle, ChainPosition, ConfirmationTimeHeightAnchor, IndexedTxGraph,
+    Persist, PersistBackend,
 };
 use bitcoin::{
-    absolute::Height, key::Secp256k1, psbt::Psbt, Address, BlockHash, Network, OutPoint, Script,
-    Transaction, TxIn, TxOut, Witness,
+    absolute::LockTime, blockdata::constants::genesis_block, key::Secp256k1, psbt::Psbt, Address,
+    Block, BlockHash, Network, OutPoint, Transaction, TxOut, Txid,
 };
-use bitcoind::bitcoincore_rpc::{Client, RpcApi};
+use bitcoind::bitcoincore_rpc::{self, jsonrpc, Client, RpcApi};
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::BTreeMap,
-    fs::OpenOptions,
-    io::{BufReader, BufWriter},
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+    io::{BufRead, Write},
     path::PathBuf,
+    str::FromStr,
     sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
-const DENOMINATION: u64 = 100_000_000;
+/// How far ahead of the network time bitcoind accepts block timestamps, in seconds.
+const MAX_FUTURE_BLOCK_TIME: u64 = 2 * 60 * 60;
+
+/// bitcoind's `RPC_INVALID_PARAMETER`, returned by `getblockhash` for heights above the tip.
+const RPC_INVALID_PARAMETER: i32 = -8;
+
+/// Block hashes kept by `Oracle`, enough for reorg walks without holding a whole scan.
+const MAX_CACHED_BLOCK_HASHES: usize = 1_000;
+
+/// bitcoind's `RPC_INVALID_ADDRESS_OR_KEY`, returned by `getrawtransaction` for unknown txids.
+const RPC_INVALID_ADDRESS_OR_KEY: i32 = -5;
+
+/// Blocks before a coinbase output can be spent.
+const COINBASE_MATURITY: u32 = 100;
+
+/// Transactions a replacement may evict at most, the original and its descendants
+/// (BIP125 rule 5).
+const MAX_REPLACEMENT_CANDIDATES: usize = 100;
+
+/// bitcoind's `sendrawtransaction` rejection codes.
+const RPC_VERIFY_ERROR: i32 = -25;
+const RPC_VERIFY_REJECTED: i32 = -26;
+const RPC_VERIFY_ALREADY_IN_CHAIN: i32 = -27;
 
 use super::{
-    error::WalletError, keychain_kind::KeychainKind, signer::into_wallet_descriptor_checked,
+    backend::{BackendKind, WalletBackend},
+    coin_selection::{fee_for_weight, CoinSelectionAlgorithm, TXIN_BASE_WEIGHT},
+    error::{Bip125Error, BroadcastError, WalletError},
+    fee_rate::{FeeRate, MIN_RELAY_FEE_RATE},
+    fidelity::FidelityBond,
+    keychain_kind::KeychainKind,
+    keychain_store::{KeychainStore, RecoveryReport},
+    labels::{Bip329Record, Label, LabelType},
+    signer::into_wallet_descriptor_checked,
+    spk_window::SpkWindow,
+    sqlite_store::SqliteStore,
+    tx_builder::{TxBuilder, TxDetails},
 };
 
 pub struct Oracle {
     pub rpc: Client,
+    cache: RefCell<BlockHashCache>,
+}
+
+/// Block hashes of bitcoind's active chain, valid for as long as `tip` is the
+/// tip we are asked about. A different tip (new block or reorg) resets it.
+#[derive(Default)]
+struct BlockHashCache {
+    tip: Option<BlockId>,
+    hashes: BTreeMap<u32, BlockHash>,
+}
+
+impl Oracle {
+    pub fn new(rpc: Client) -> Self {
+        Self {
+            rpc,
+            cache: RefCell::new(BlockHashCache::default()),
+        }
+    }
+
+    /// Hash of the block at `height` in bitcoind's active chain, or `None` if the
+    /// node does not have a block at that height. Cached hashes are only as fresh as
+    /// the last `get_chain_tip`, which callers have to make first.
+    fn block_hash_at(&self, height: u32) -> Result<Option<BlockHash>, bitcoind::Error> {
+        if let Some(hash) = self.cache.borrow().hashes.get(&height) {
+            return Ok(Some(*hash));
+        }
+
+        let hash = match self.rpc.get_block_hash(height as u64) {
+            Ok(hash) => hash,
+            Err(err) if rpc_error_code(&err) == Some(RPC_INVALID_PARAMETER) => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        self.cache_block_hash(height, hash);
+
+        Ok(Some(hash))
+    }
+
+    /// Fetches a transaction from the node's mempool, or `None` if it has left it
+    /// since the caller learned its txid.
+    pub fn mempool_tx(&self, txid: &Txid) -> Result<Option<Transaction>, bitcoind::Error> {
+        match self.rpc.get_raw_transaction(txid, None) {
+            Ok(tx) => Ok(Some(tx)),
+            Err(err) if rpc_error_code(&err) == Some(RPC_INVALID_ADDRESS_OR_KEY) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// bitcoind's fee rate estimate in sat/vB for confirming within `target` blocks, or
+    /// `None` if it has not seen enough transactions to make one.
+    pub fn estimate_fee_rate(&self, target: u16) -> Result<Option<f32>, bitcoind::Error> {
+        let estimate = self.rpc.estimate_smart_fee(target, None)?;
+
+        // reported in BTC/kvB
+        Ok(estimate
+            .fee_rate
+            .map(|fee_rate| fee_rate.to_sat() as f32 / 1000.0))
+    }
+
+    /// Submits `tx` to the node's mempool.
+    pub fn broadcast(&self, tx: &Transaction) -> Result<Txid, WalletError> {
+        match self.rpc.send_raw_transaction(tx) {
+            Ok(txid) => Ok(txid),
+            Err(bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(err))) => {
+                Err(broadcast_error(err.code, err.message).into())
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Fee paid by the mempool transaction `txid`, for when the graph does not know all
+    /// of its inputs.
+    pub fn mempool_fee(&self, txid: &Txid) -> Result<u64, bitcoind::Error> {
+        Ok(self.rpc.get_mempool_entry(txid)?.fees.base.to_sat())
+    }
+
+    /// Drops every cached block hash, e.g. after the caller learned about a reorg.
+    pub fn clear_cache(&self) {
+        *self.cache.borrow_mut() = BlockHashCache::default();
+    }
+
+    /// Keeps the highest heights, the ones reorgs touch.
+    fn cache_block_hash(&self, height: u32, hash: BlockHash) {
+        let mut cache = self.cache.borrow_mut();
+
+        cache.hashes.insert(height, hash);
+        while cache.hashes.len() > MAX_CACHED_BLOCK_HASHES {
+            cache.hashes.pop_first();
+        }
+    }
+
+    fn reset_cache_for_tip(&self, chain_tip: BlockId) {
+        let mut cache = self.cache.borrow_mut();
+
+        if cache.tip != Some(chain_tip) {
+            cache.tip = Some(chain_tip);
+            cache.hashes.clear();
+        }
+    }
+}
+
+fn rpc_error_code(err: &bitcoincore_rpc::Error) -> Option<i32> {
+    match err {
+        bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(err)) => Some(err.code),
+        _ => None,
+    }
+}
+
+fn broadcast_error(code: i32, reason: String) -> BroadcastError {
+    match code {
+        RPC_VERIFY_ALREADY_IN_CHAIN => BroadcastError::AlreadyInChain,
+        RPC_VERIFY_ERROR if reason.contains("missing") => BroadcastError::MissingInputs,
+        RPC_VERIFY_REJECTED if reason.contains("fee") => BroadcastError::FeeTooLow(reason),
+        RPC_VERIFY_REJECTED if reason.contains("txn-mempool-conflict") => {
+            BroadcastError::MempoolConflict
+        }
+        RPC_VERIFY_REJECTED if reason.contains("non-final") => BroadcastError::NonFinal,
+        RPC_VERIFY_REJECTED if reason.contains("dust") => BroadcastError::Dust,
+        _ => BroadcastError::Rejected { code, reason },
+    }
+}
+
+/// Checks the rules bitcoind applies to `replacement` (BIP125 rules 3 to 6).
+/// `replaced_fee` and `replaced_count` cover the original and the unconfirmed
+/// descendants evicted with it.
+fn check_replacement(
+    original: &Transaction,
+    original_fee: u64,
+    replaced_fee: u64,
+    replaced_count: usize,
+    replacement: &Transaction,
+    replacement_fee: u64,
+) -> Result<(), Bip125Error> {
+    if replaced_count > MAX_REPLACEMENT_CANDIDATES {
+        return Err(Bip125Error::TooManyReplacements(replaced_count));
+    }
+
+    // the replacement has to pay for its own relay on top of what the evicted ones paid
+    let needed =
+        replaced_fee + fee_for_weight(MIN_RELAY_FEE_RATE, replacement.weight().to_wu() as usize);
+    if replacement_fee < needed {
+        return Err(Bip125Error::InsufficientFee {
+            needed,
+            paid: replacement_fee,
+        });
+    }
+
+    let original_rate = original_fee as f64 / original.vsize() as f64;
+    let replacement_rate = replacement_fee as f64 / replacement.vsize() as f64;
+    if replacement_rate <= original_rate {
+        return Err(Bip125Error::FeeRateNotHigher);
+    }
+
+    Ok(())
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
 }
 
 impl ChainOracle for Oracle {
@@ -37,24 +234,37 @@ impl ChainOracle for Oracle {
 
     fn get_chain_tip(&self) -> Result<bdk_chain::BlockId, Self::Error> {
         let block_chain_info = self.rpc.get_blockchain_info()?;
-        Ok(BlockId {
+        let chain_tip = BlockId {
             hash: block_chain_info.best_block_hash,
             height: block_chain_info.blocks as u32,
-        })
+        };
+
+        self.reset_cache_for_tip(chain_tip);
+        self.cache_block_hash(chain_tip.height, chain_tip.hash);
+
+        Ok(chain_tip)
     }
+
     fn is_block_in_chain(
         &self,
         block: bdk_chain::BlockId,
         chain_tip: bdk_chain::BlockId,
     ) -> Result<Option<bool>, Self::Error> {
-        Ok(Some(true))
-    }
-}
+        self.reset_cache_for_tip(chain_tip);
 
-impl Default for Changeset {
-    fn default() -> Self {
-        Self {
-            indexed_tx_graph: indexed_tx_graph::ChangeSet::default(),
+        // the tip itself has been reorged out, we cannot say anything about the block
+        match self.block_hash_at(chain_tip.height)? {
+            Some(hash) if hash == chain_tip.hash => {}
+            _ => return Ok(None),
+        }
+
+        if block.height > chain_tip.height {
+            return Ok(Some(false));
+        }
+
+        match self.block_hash_at(block.height)? {
+            Some(hash) => Ok(Some(hash == block.hash)),
+            None => Ok(None),
         }
     }
 }
@@ -62,29 +272,134 @@ impl Default for Changeset {
 impl Append for Changeset {
     fn is_empty(&self) -> bool {
         self.indexed_tx_graph.is_empty()
+            && self.local_chain.is_empty()
+            && self.mempool_evictions.is_empty()
+            && self.birthday.is_none()
+            && self.descriptors.is_empty()
+            && self.added_descriptors.is_empty()
+            && self.network.is_none()
+            && self.frozen.is_empty()
+            && self.labels.is_empty()
     }
     fn append(&mut self, other: Self) {
-        Append::append(&mut self.indexed_tx_graph, other.indexed_tx_graph)
+        Append::append(&mut self.indexed_tx_graph, other.indexed_tx_graph);
+        Append::append(&mut self.local_chain, other.local_chain);
+        Append::append(&mut self.mempool_evictions, other.mempool_evictions);
+        if other.birthday.is_some() {
+            self.birthday = other.birthday;
+        }
+        Append::append(&mut self.descriptors, other.descriptors);
+        Append::append(&mut self.added_descriptors, other.added_descriptors);
+        if other.network.is_some() {
+            self.network = other.network;
+        }
+        Append::append(&mut self.frozen, other.frozen);
+        Append::append(&mut self.labels, other.labels);
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
 pub struct Changeset {
-    indexed_tx_graph: indexed_tx_graph::ChangeSet<
+    pub(crate) indexed_tx_graph: indexed_tx_graph::ChangeSet<
         ConfirmationTimeHeightAnchor,
         keychain::ChangeSet<KeychainKind>,
     >,
+    // wallets written before checkpoints were tracked have no chain data
+    #[serde(default)]
+    pub(crate) local_chain: local_chain::ChangeSet,
+    // unconfirmed txid -> unix time at which it was no longer in the node's mempool
+    #[serde(default)]
+    pub(crate) mempool_evictions: BTreeMap<Txid, u64>,
+    #[serde(default)]
+    pub(crate) birthday: Option<Birthday>,
+    // public descriptor of each keychain, checked against the caller's on open
+    #[serde(default)]
+    pub(crate) descriptors: BTreeMap<KeychainKind, String>,
+    // descriptor of each keychain added through `WalletStore::add_descriptor`, since the
+    // caller of `WalletStore::new` does not pass them again. Secret keys are only kept
+    // in an encrypted wallet file, a plaintext one holds the public descriptor.
+    #[serde(default)]
+    pub(crate) added_descriptors: BTreeMap<KeychainKind, String>,
+    #[serde(default)]
+    pub(crate) network: Option<Network>,
+    // outpoint -> whether it was frozen (true) or unfrozen (false) last
+    #[serde(default)]
+    pub(crate) frozen: BTreeMap<OutPoint, bool>,
+    // a `None` label was removed
+    #[serde(default)]
+    pub(crate) labels: BTreeMap<(LabelType, String), Option<Label>>,
+}
+
+/// When the wallet was created. Blocks before it cannot pay to the wallet and are
+/// skipped by `WalletStore::full_scan`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Birthday {
+    Height(u32),
+    /// Unix time, resolved to the first block that could have been mined after it.
+    Time(u64),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct WalletBalance {
+    pub total: Balance,
+    pub per_keychain: BTreeMap<KeychainKind, Balance>,
+}
+
+/// An unspent output of the wallet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalUtxo {
+    pub outpoint: OutPoint,
+    pub txout: TxOut,
+    pub keychain: KeychainKind,
+    pub derivation_index: u32,
+    /// `None` while unconfirmed.
+    pub confirmation_height: Option<u32>,
+    /// Frozen coins are only spent when selected explicitly.
+    pub frozen: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxKind {
+    Payment,
+    /// Touches a swapcoin keychain.
+    Swap,
+    /// Creates or spends a fidelity bond.
+    Fidelity,
+}
+
+/// A wallet transaction as listed by `WalletStore::transactions`.
+#[derive(Debug, Clone)]
+pub struct TxHistoryItem {
+    pub txid: Txid,
+    /// `None` while unconfirmed.
+    pub confirmation_height: Option<u32>,
+    pub confirmation_time: Option<u64>,
+    /// Received minus spent, for each keychain the transaction touches.
+    pub net: BTreeMap<KeychainKind, i64>,
+    /// `None` when the value of an input that is not ours is unknown.
+    pub fee: Option<u64>,
+    pub kind: TxKind,
 }
 
 pub struct WalletStore {
     signers: BTreeMap<KeychainKind, Arc<SignersContainer>>,
     pub indexed_graph:
         IndexedTxGraph<ConfirmationTimeHeightAnchor, KeychainTxOutIndex<KeychainKind>>,
+    pub chain: LocalChain,
+    mempool_evictions: BTreeMap<Txid, u64>,
+    // mempool txids fetched by `sync_mempool` that did not concern the wallet, as long as
+    // it watches the script pubkeys of `inspected_mempool_watch`
+    inspected_mempool_txids: BTreeSet<Txid>,
+    inspected_mempool_watch: BTreeMap<KeychainKind, (Option<u32>, u32)>,
+    birthday: Option<Birthday>,
+    frozen: BTreeSet<OutPoint>,
+    labels: BTreeMap<(LabelType, String), Label>,
+    lookahead: SpkWindow,
+    backend: WalletBackend,
     network: Network,
     pub oracle: Oracle,
     pub file_name: PathBuf,
-    path: PathBuf,
-    pub persist: Persist<KeychainStore, Changeset>,
+    pub persist: Persist<WalletBackend, Changeset>,
 }
 
 impl
@@ -101,65 +416,166 @@ impl
             keychain::ChangeSet<KeychainKind>,
         >,
     ) -> Self {
-        Self { indexed_tx_graph }
+        Self {
+            indexed_tx_graph,
+            ..Default::default()
+        }
+    }
+}
+
+impl From<local_chain::ChangeSet> for Changeset {
+    fn from(local_chain: local_chain::ChangeSet) -> Self {
+        Self {
+            local_chain,
+            ..Default::default()
+        }
     }
 }
 
 impl WalletStore {
+    /// Opens the wallet stored under `path`, or creates it. `birthday` is recorded unless
+    /// the wallet has one already: the node's tip height for a newly generated seed, or
+    /// when a restored seed was generated. Without one `full_scan` starts at genesis.
+    // each argument is a separate choice of the caller, none of them have defaults
+    #[allow(clippy::too_many_arguments)]
     pub fn new<E: IntoWalletDescriptor>(
         network: Network,
         descriptors: BTreeMap<KeychainKind, E>,
         rpc: Client,
         file_name: PathBuf,
         path: PathBuf,
+        backend: BackendKind,
+        passphrase: Option<&str>,
+        birthday: Option<Birthday>,
     ) -> Result<Self, WalletError> {
-        let keychain_index = KeychainTxOutIndex::<KeychainKind>::default();
+        // unrevealed script pubkeys are only watched through `lookahead`, which is set per
+        // keychain. The index's own lookahead is the same for all of them.
+        let keychain_index = KeychainTxOutIndex::<KeychainKind>::new(0);
         let mut indexed_graph = IndexedTxGraph::new(keychain_index);
 
         let secp = Secp256k1::new();
 
         //iterate over each keychain and create a signer for that keychain
         let mut signers = BTreeMap::<KeychainKind, Arc<SignersContainer>>::new();
+        let mut lookahead = SpkWindow::default();
+        let mut public_descriptors = BTreeMap::<KeychainKind, String>::new();
         for (keychain_kind, descriptor) in descriptors {
             let (descriptor, keymap) = into_wallet_descriptor_checked(descriptor, &secp, network)?;
             let signer = Arc::new(SignersContainer::build(keymap, &descriptor, &secp));
 
+            public_descriptors.insert(keychain_kind.clone(), descriptor.to_string());
             indexed_graph
                 .index
                 .add_keychain(keychain_kind.clone(), descriptor);
+            lookahead.set_gap(keychain_kind.clone(), keychain_kind.default_lookahead());
             signers.insert(keychain_kind, signer);
         }
 
-        let mut keychain_store = KeychainStore {
-            file_name: file_name.clone(),
-            path: path.clone(),
+        let mut backend = match (backend, passphrase) {
+            (BackendKind::File, Some(passphrase)) => WalletBackend::File(
+                KeychainStore::with_passphrase(file_name.clone(), path.clone(), passphrase)?,
+            ),
+            (BackendKind::File, None) => {
+                WalletBackend::File(KeychainStore::new(file_name.clone(), path.clone()))
+            }
+            (BackendKind::Sqlite, Some(_)) => return Err(WalletError::EncryptionUnsupported),
+            (BackendKind::Sqlite, None) => WalletBackend::Sqlite(SqliteStore::open(
+                &path.join(&file_name).with_extension("sqlite"),
+            )?),
         };
 
-        let initial_changeset = match keychain_store.load_from_persistence()? {
-            Some(changeset) => changeset,
-            None => Changeset::default(),
-        };
+        let initial_changeset = backend.load_from_persistence()?.unwrap_or_default();
 
         // println!("initial changeset: {:?}", initial_changeset);
 
-        let mut persist = Persist::<KeychainStore, Changeset>::new(keychain_store);
+        if let Some(stored) = initial_changeset.network {
+            if stored != network {
+                return Err(WalletError::NetworkMismatch { stored, network });
+            }
+        }
+
+        for (keychain, descriptor) in &public_descriptors {
+            match initial_changeset.descriptors.get(keychain) {
+                Some(stored) if stored != descriptor => {
+                    return Err(WalletError::DescriptorMismatch(keychain.clone()));
+                }
+                _ => {}
+            }
+        }
+
+        let mut persist = Persist::<WalletBackend, Changeset>::new(backend.try_clone()?);
+
+        // new wallets, and wallets written before descriptors were stored, record them now
+        public_descriptors
+            .retain(|keychain, _| !initial_changeset.descriptors.contains_key(keychain));
+        persist.stage_and_commit(Changeset {
+            descriptors: public_descriptors,
+            network: Some(network).filter(|_| initial_changeset.network.is_none()),
+            birthday: birthday.filter(|_| initial_changeset.birthday.is_none()),
+            ..Default::default()
+        })?;
+
+        // the keychains have to be known before their revealed indices are applied. Ones
+        // stored without their keys are watch-only until their descriptor is added again
+        for (keychain, descriptor) in &initial_changeset.added_descriptors {
+            if indexed_graph.index.keychains().contains_key(keychain) {
+                continue;
+            }
+
+            let (descriptor, keymap) =
+                into_wallet_descriptor_checked(descriptor.as_str(), &secp, network)?;
+
+            if !keymap.is_empty() {
+                let signer = Arc::new(SignersContainer::build(keymap, &descriptor, &secp));
+                signers.insert(keychain.clone(), signer);
+            }
+
+            indexed_graph
+                .index
+                .add_keychain(keychain.clone(), descriptor);
+            lookahead.set_gap(keychain.clone(), keychain.default_lookahead());
+        }
+
+        let (mut chain, _) = LocalChain::from_genesis_hash(genesis_block(network).block_hash());
+        chain.apply_changeset(&initial_changeset.local_chain)?;
 
         indexed_graph.apply_changeset(initial_changeset.indexed_tx_graph);
 
-        persist.stage(Changeset::from(indexed_graph.initial_changeset()));
-        persist.commit()?;
+        let mempool_evictions = initial_changeset.mempool_evictions;
+        let birthday = initial_changeset.birthday.or(birthday);
+        let frozen = initial_changeset
+            .frozen
+            .into_iter()
+            .filter_map(|(outpoint, frozen)| frozen.then_some(outpoint))
+            .collect();
+        let labels = initial_changeset
+            .labels
+            .into_iter()
+            .filter_map(|(key, label)| Some((key, label?)))
+            .collect();
 
         Ok(Self {
             signers,
             indexed_graph,
-            oracle: Oracle { rpc },
+            chain,
+            mempool_evictions,
+            inspected_mempool_txids: BTreeSet::new(),
+            inspected_mempool_watch: BTreeMap::new(),
+            birthday,
+            frozen,
+            labels,
+            lookahead,
+            backend,
+            oracle: Oracle::new(rpc),
             network,
             file_name,
-            path,
             persist,
         })
     }
 
+    /// Adds a keychain, e.g. for a swapcoin. It is restored when the wallet is reopened.
+    /// An encrypted wallet file keeps its secret keys too, a plaintext one only the public
+    /// descriptor, so there the keychain is watch-only until it is added again.
     pub fn add_descriptor<E: IntoWalletDescriptor>(
         &mut self,
         keychain: KeychainKind,
@@ -168,27 +584,133 @@ impl WalletStore {
         let (descriptor, keymap) =
             into_wallet_descriptor_checked(descriptor_to_add, &Secp256k1::new(), self.network)?;
 
+        let stored_descriptor = if self.backend.is_encrypted() {
+            descriptor.to_string_with_secret(&keymap)
+        } else {
+            descriptor.to_string()
+        };
+
         let signer = Arc::new(SignersContainer::build(
             keymap,
             &descriptor,
             &Secp256k1::new(),
         ));
 
+        // re-adding a keychain restored from the wallet file brings back its signer, and
+        // stores its keys if they were added before the file was encrypted
+        if let Some(existing) = self.indexed_graph.index.keychains().get(&keychain) {
+            if *existing != descriptor {
+                return Err(WalletError::DescriptorMismatch(keychain));
+            }
+            self.signers.insert(keychain.clone(), signer);
+            if self.backend.is_encrypted() {
+                self.persist.stage_and_commit(Changeset {
+                    added_descriptors: BTreeMap::from([(keychain, stored_descriptor)]),
+                    ..Default::default()
+                })?;
+            }
+            return Ok(());
+        }
+
+        let changeset = Changeset {
+            descriptors: BTreeMap::from([(keychain.clone(), descriptor.to_string())]),
+            added_descriptors: BTreeMap::from([(keychain.clone(), stored_descriptor)]),
+            ..Default::default()
+        };
+
         self.indexed_graph
             .index
             .add_keychain(keychain.clone(), descriptor);
+        self.lookahead
+            .set_gap(keychain.clone(), keychain.default_lookahead());
         self.signers.insert(keychain, signer);
 
+        self.persist.stage_and_commit(changeset)?;
+
         Ok(())
     }
 
+    /// Set when the wallet file had a torn trailing record that was discarded on open.
+    pub fn recovery(&self) -> Option<RecoveryReport> {
+        match &self.backend {
+            WalletBackend::File(store) => store.recovery(),
+            WalletBackend::Sqlite(_) => None,
+        }
+    }
+
+    /// The SQLite database, when the wallet was opened with `BackendKind::Sqlite`.
+    pub fn sqlite(&self) -> Option<&SqliteStore> {
+        match &self.backend {
+            WalletBackend::Sqlite(store) => Some(store),
+            WalletBackend::File(_) => None,
+        }
+    }
+
+    /// Folds the changesets appended to the wallet file so far into a single record.
+    pub fn compact(&mut self) -> Result<usize, WalletError> {
+        self.persist.commit()?;
+
+        match &mut self.backend {
+            WalletBackend::File(store) => store.compact(),
+            // rows are updated in place, there is nothing to fold
+            WalletBackend::Sqlite(_) => Ok(0),
+        }
+    }
+
+    /// Re-encrypts the wallet file under `new_passphrase`, or decrypts it if `None`.
+    pub fn change_passphrase(
+        &mut self,
+        old_passphrase: Option<&str>,
+        new_passphrase: Option<&str>,
+    ) -> Result<(), WalletError> {
+        let store = match &mut self.backend {
+            WalletBackend::File(store) => store,
+            WalletBackend::Sqlite(_) => return Err(WalletError::EncryptionUnsupported),
+        };
+
+        store.check_passphrase(old_passphrase)?;
+
+        // a plaintext file only keeps the public descriptors of added keychains
+        if store.is_encrypted() && new_passphrase.is_none() {
+            let added_descriptors = store
+                .load_from_persistence()?
+                .unwrap_or_default()
+                .added_descriptors
+                .into_keys()
+                .filter_map(|keychain| {
+                    let descriptor = self.indexed_graph.index.keychains().get(&keychain)?;
+                    Some((keychain, descriptor.to_string()))
+                })
+                .collect();
+            self.persist.stage(Changeset {
+                added_descriptors,
+                ..Default::default()
+            });
+        }
+
+        self.persist.commit()?;
+
+        store.change_passphrase(old_passphrase, new_passphrase)?;
+
+        // later commits have to be written with the new key
+        self.persist = Persist::new(self.backend.try_clone()?);
+
+        Ok(())
+    }
+
+    /// Sets how many script pubkeys past the last revealed index of `keychain` are
+    /// watched for payments. Matches reveal the index, so the window moves along.
+    pub fn set_lookahead(&mut self, keychain: KeychainKind, lookahead: u32) {
+        self.lookahead.set_gap(keychain, lookahead);
+    }
+
     pub fn descriptors(&self) -> Vec<String> {
         let descriptors = self
             .indexed_graph
             .index
             .keychains()
-            .iter()
-            .map(|(_, descriptor)| descriptor.to_string())
+            .values()
+            .map(|descriptor| descriptor.to_string())
             .collect::<Vec<String>>();
 
         descriptors
@@ -262,112 +784,338 @@ impl WalletStore {
         Ok(())
     }
 
-    pub fn send_to_address(&mut self, to_address: Address, amount: u64) -> Result<(), WalletError> {
-        let utxos = self.get_utxos(KeychainKind::External).unwrap();
-
-        let mut sum = 0;
-        let mut outpoints = vec![];
+    /// Starts building a transaction that spends the wallet's coins.
+    pub fn build_tx(&mut self) -> TxBuilder<'_> {
+        TxBuilder::new(self)
+    }
 
-        for (outpoint, txout) in utxos {
-            if sum < amount {
-                sum += txout.value;
+    /// Pays `amount` to `to_address`. If `utxos` is not empty, only those coins are
+    /// spent, frozen or not. Returns what the broadcast transaction moved.
+    pub fn send_to_address(
+        &mut self,
+        to_address: Address,
+        amount: u64,
+        fee_rate: FeeRate,
+        coin_selection: CoinSelectionAlgorithm,
+        utxos: &[OutPoint],
+    ) -> Result<TxDetails, WalletError> {
+        let mut builder = self.build_tx();
+        builder
+            .add_recipient(to_address.script_pubkey(), amount)
+            .fee_rate(fee_rate)
+            .coin_selection(coin_selection);
+        if !utxos.is_empty() {
+            for outpoint in utxos {
+                builder.add_utxo(*outpoint);
+            }
+            builder.manually_selected_only();
+        }
+        let (mut psbt, details) = builder.finish()?;
 
-                outpoints.push(outpoint);
-            };
+        if !self.sign(&mut psbt, SignOptions::default())? {
+            return Err(WalletError::IncompleteSignature);
         }
 
-        println!("sum: {:?}", sum);
+        self.broadcast(&psbt.extract_tx())?;
 
-        let index = &mut self.indexed_graph.index;
+        Ok(details)
+    }
 
-        let last_revealed_index = index
-            .last_revealed_index(&KeychainKind::External)
-            .unwrap_or(0);
+    /// Hands `tx` to bitcoind and records it as unconfirmed, so that its inputs are not
+    /// selected again and its change can be spent before it confirms.
+    pub fn broadcast(&mut self, tx: &Transaction) -> Result<Txid, WalletError> {
+        let txid = self.oracle.broadcast(tx)?;
+
+        // a tx that was evicted before has to be newer than its eviction to count again
+        let seen_at = match self.mempool_evictions.get(&txid) {
+            Some(evicted_at) => unix_now().max(*evicted_at),
+            None => unix_now(),
+        };
+
+        let mut changeset = self.reveal_lookahead_matches([tx])?;
 
-        let ((next_external_index, from_address), external_changeset) =
-            index.reveal_next_spk(&KeychainKind::External);
+        changeset.append(Changeset::from(
+            self.indexed_graph
+                .batch_insert_relevant_unconfirmed([(tx, seen_at)]),
+        ));
 
-        println!("Next unused SPK: {:?}", next_external_index);
-        println!("from_address: {:?}", from_address);
-        println!("{:?}", external_changeset);
+        self.persist.stage_and_commit(changeset)?;
 
-        let ((next_internal_index, change_address), internal_changeset) =
-            index.reveal_next_spk(&KeychainKind::Internal);
+        Ok(txid)
+    }
 
-        println!("Next unused SPK: {:?}", next_internal_index);
-        println!("change_address: {:?}", change_address);
-        println!("{:?}", internal_changeset);
+    /// Fills in what signers need for every input spending one of the wallet's coins and
+    /// signs it with the keychain paid to. Inputs of other wallets are left alone. Returns
+    /// whether the PSBT could be finalized.
+    pub fn sign(&self, psbt: &mut Psbt, sign_options: SignOptions) -> Result<bool, WalletError> {
+        let secp = Secp256k1::new();
+        let graph = self.indexed_graph.graph();
 
-        let mut inputs = vec![];
+        let mut keychains = BTreeSet::new();
+        for input_index in 0..psbt.inputs.len() {
+            let previous_output = psbt.unsigned_tx.input[input_index].previous_output;
 
-        for outpoint in outpoints {
-            let input = TxIn {
-                previous_output: outpoint,
-                script_sig: Default::default(),
-                sequence: bitcoin::Sequence::max_value(),
-                witness: Witness::default(),
+            let Some(txout) = graph.get_txout(previous_output) else {
+                continue;
             };
+            let Some((keychain, derivation_index)) =
+                self.indexed_graph.index.index_of_spk(&txout.script_pubkey)
+            else {
+                continue;
+            };
+
+            let descriptor = self
+                .indexed_graph
+                .index
+                .keychains()
+                .get(&keychain)
+                .ok_or_else(|| WalletError::MissingKeychain(keychain.clone()))?;
+            let derived_descriptor = descriptor.at_derivation_index(derivation_index)?;
+
+            let input = &mut psbt.inputs[input_index];
+            input.witness_utxo = Some(txout.clone());
+            // segwit v0 signers want the whole previous transaction to commit to the amount
+            input.non_witness_utxo = graph.get_tx(previous_output.txid).cloned();
 
-            inputs.push(input);
+            psbt.update_input_with_descriptor(input_index, &derived_descriptor)?;
+
+            keychains.insert(keychain.clone());
         }
 
-        let mut outputs = vec![];
+        for keychain in &keychains {
+            if let Some(signer_container) = self.signers.get(keychain) {
+                for signer in signer_container.signers() {
+                    signer.sign_transaction(psbt, &sign_options, &secp)?;
+                }
+            }
+        }
 
-        let send_output = TxOut {
-            value: amount,
-            script_pubkey: to_address.script_pubkey(),
-        };
+        if !sign_options.try_finalize {
+            return Ok(false);
+        }
 
-        let change_output = TxOut {
-            value: (sum - amount),
-            script_pubkey: change_address.to_owned(),
-        };
+        Ok(psbt.finalize_mut(&secp).is_ok())
+    }
 
-        outputs.push(send_output);
-        outputs.push(change_output);
+    /// Replaces the unconfirmed wallet transaction `txid` with one paying `fee_rate`,
+    /// following BIP125. The recipients are kept; the extra fee comes out of the change or
+    /// from additional confirmed inputs. Unconfirmed spends of its outputs are evicted with
+    /// it, so the replacement also pays their fees.
+    pub fn bump_fee(&mut self, txid: Txid, fee_rate: FeeRate) -> Result<Txid, WalletError> {
+        let graph = self.indexed_graph.graph();
+        let original = graph
+            .get_tx(txid)
+            .cloned()
+            .ok_or(WalletError::UnknownTransaction(txid))?;
+
+        let chain_tip = self.oracle.get_chain_tip()?;
+        match graph.try_get_chain_position(&self.oracle, chain_tip, txid)? {
+            Some(ChainPosition::Unconfirmed(_)) => {}
+            Some(ChainPosition::Confirmed(_)) => {
+                return Err(WalletError::TransactionConfirmed(txid))
+            }
+            None => return Err(WalletError::UnknownTransaction(txid)),
+        }
 
-        let tx = Transaction {
-            version: 2,
-            lock_time: bitcoin::absolute::LockTime::Blocks(Height::min_value()),
-            input: inputs,
-            output: outputs,
-        };
+        if !original.is_explicitly_rbf() {
+            return Err(Bip125Error::NotReplaceable.into());
+        }
 
-        // signer.signers().get
-        if let Some(external_signer_container) = self.signers.get(&KeychainKind::External) {
-            let secp = Secp256k1::new();
-            let external_signers = external_signer_container.signers();
-            for external_signer in external_signers {
-                let mut psbt = Psbt::from_unsigned_tx(tx.clone())?;
-                // psbt.inputs[0].bip32_derivation = external_signer.
-                let descriptor = self
-                    .indexed_graph
-                    .index
-                    .keychains()
-                    .get(&KeychainKind::External)
-                    .unwrap();
+        let original_fee = self.fee_of(&original)?;
+
+        // spends of the original's outputs, e.g. of its change, are evicted with it
+        let mut replaced_fee = original_fee;
+        let mut replaced_count = 1;
+        let descendants = graph
+            .walk_descendants(txid, |_, descendant| Some(descendant))
+            .collect::<Vec<_>>();
+        for descendant in descendants {
+            if self.is_evicted(&descendant) {
+                continue;
+            }
+            match graph.try_get_chain_position(&self.oracle, chain_tip, descendant)? {
+                Some(ChainPosition::Unconfirmed(_)) => {}
+                _ => continue,
+            }
+
+            let tx = graph
+                .get_tx(descendant)
+                .ok_or(WalletError::UnknownTransaction(descendant))?;
+            // e.g. a swap tx spending our change along with the counterparty's coins
+            replaced_fee += match self.fee_of(tx) {
+                Ok(fee) => fee,
+                Err(WalletError::UnknownUtxo(_)) => self.oracle.mempool_fee(&descendant)?,
+                Err(err) => return Err(err),
+            };
+            replaced_count += 1;
+        }
+
+        // the change output is recreated by the builder
+        let recipients = original
+            .output
+            .iter()
+            .filter(|txout| {
+                !matches!(
+                    self.indexed_graph.index.index_of_spk(&txout.script_pubkey),
+                    Some((KeychainKind::Internal, _))
+                )
+            })
+            .map(|txout| (txout.script_pubkey.clone(), txout.value))
+            .collect::<Vec<_>>();
+
+        let mut builder = self.build_tx();
+        builder
+            .replace(original.clone(), replaced_fee)
+            .fee_rate(fee_rate)
+            .version(original.version)
+            .nlocktime(original.lock_time);
+        for txin in &original.input {
+            builder.add_utxo(txin.previous_output);
+        }
+        for (script_pubkey, amount) in recipients {
+            builder.add_recipient(script_pubkey, amount);
+        }
+        let (mut psbt, details) = builder.finish()?;
 
-                let derived_descriptor = descriptor.at_derivation_index(last_revealed_index)?;
+        if !self.sign(&mut psbt, SignOptions::default())? {
+            return Err(WalletError::IncompleteSignature);
+        }
 
-                println!("{:?}", psbt.inputs.get_mut(0).unwrap().witness_utxo);
-                println!("{:?}", psbt.inputs.get_mut(0).unwrap().non_witness_utxo);
+        let replacement = psbt.extract_tx();
 
-                psbt.update_input_with_descriptor(0, &derived_descriptor)?;
-                // let secret = external_signer.descriptor_secret_key().unwrap();
+        check_replacement(
+            &original,
+            original_fee,
+            replaced_fee,
+            replaced_count,
+            &replacement,
+            details.fee,
+        )?;
 
-                // psbt.sign(&secret, &secp);
+        self.broadcast(&replacement)
+    }
 
-                external_signer.sign_transaction(&mut psbt, &SignOptions::default(), &secp)?;
+    /// Speeds up the unconfirmed `txid`, usually a payment to us that we cannot replace, by
+    /// spending our outputs of it back to the Internal keychain with a fee high enough for
+    /// the parent and child together to pay `package_fee_rate`. Frozen outputs are left
+    /// alone, a parent with only frozen outputs for us cannot be sped up.
+    pub fn cpfp(&mut self, txid: Txid, package_fee_rate: FeeRate) -> Result<Txid, WalletError> {
+        let graph = self.indexed_graph.graph();
+        let parent = graph
+            .get_tx(txid)
+            .cloned()
+            .ok_or(WalletError::UnknownTransaction(txid))?;
+
+        let chain_tip = self.oracle.get_chain_tip()?;
+        match graph.try_get_chain_position(&self.oracle, chain_tip, txid)? {
+            Some(ChainPosition::Unconfirmed(_)) => {}
+            Some(ChainPosition::Confirmed(_)) => {
+                return Err(WalletError::TransactionConfirmed(txid))
+            }
+            None => return Err(WalletError::UnknownTransaction(txid)),
+        }
 
-                let finalized_psbt = psbt.finalize(&secp)?;
+        // the sender's inputs are usually not in the graph
+        let parent_fee = match self.fee_of(&parent) {
+            Ok(fee) => fee,
+            Err(WalletError::UnknownUtxo(_)) => self.oracle.mempool_fee(&txid)?,
+            Err(err) => return Err(err),
+        };
 
-                println!("final: {:?}", finalized_psbt);
+        let mut child_weight = 0;
+        let mut outpoints = vec![];
+        for keychain in [KeychainKind::External, KeychainKind::Internal] {
+            let satisfaction_weight = self.satisfaction_weight(&keychain)?;
+            for utxo in self.get_utxos(keychain)? {
+                if utxo.outpoint.txid == txid && !utxo.frozen {
+                    child_weight += TXIN_BASE_WEIGHT + satisfaction_weight;
+                    outpoints.push(utxo.outpoint);
+                }
             }
         }
 
-        println!("{:#?}", tx);
+        if outpoints.is_empty() {
+            return Err(WalletError::NoWalletOutput(txid));
+        }
 
-        Ok(())
+        // every script pubkey of a keychain has the same length
+        let change_script = self
+            .indexed_graph
+            .index
+            .keychains()
+            .get(&KeychainKind::Internal)
+            .ok_or(WalletError::MissingKeychain(KeychainKind::Internal))?
+            .at_derivation_index(0)?
+            .script_pubkey();
+        child_weight += Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![],
+            output: vec![TxOut {
+                value: 0,
+                script_pubkey: change_script,
+            }],
+        }
+        .weight()
+        .to_wu() as usize;
+
+        let (fee_rate, absolute_fee) = package_fee_rate.resolve(&self.oracle)?;
+        let package_weight = parent.weight().to_wu() as usize + child_weight;
+        let package_fee = absolute_fee.unwrap_or(0) + fee_for_weight(fee_rate, package_weight);
+
+        // the child has to be relayed on its own merits too
+        let child_fee = package_fee
+            .saturating_sub(parent_fee)
+            .max(fee_for_weight(MIN_RELAY_FEE_RATE, child_weight));
+
+        let mut builder = self.build_tx();
+        builder
+            .change_keychain(KeychainKind::Internal)
+            .manually_selected_only()
+            .drain_wallet()
+            .fee_rate(FeeRate::Absolute(child_fee));
+        for outpoint in outpoints {
+            builder.add_utxo(outpoint);
+        }
+        let (mut psbt, _) = builder.finish()?;
+
+        if !self.sign(&mut psbt, SignOptions::default())? {
+            return Err(WalletError::IncompleteSignature);
+        }
+
+        self.broadcast(&psbt.extract_tx())
+    }
+
+    /// Fee paid by `tx`, which has to spend only outputs known to the graph.
+    fn fee_of(&self, tx: &Transaction) -> Result<u64, WalletError> {
+        let mut input_value = 0;
+        for txin in &tx.input {
+            let txout = self
+                .indexed_graph
+                .graph()
+                .get_txout(txin.previous_output)
+                .ok_or(WalletError::UnknownUtxo(txin.previous_output))?;
+            input_value += txout.value;
+        }
+
+        let output_value: u64 = tx.output.iter().map(|txout| txout.value).sum();
+
+        Ok(input_value.saturating_sub(output_value))
+    }
+
+    /// Upper bound on the weight that satisfying an output of `keychain` adds to an input.
+    pub(crate) fn satisfaction_weight(
+        &self,
+        keychain: &KeychainKind,
+    ) -> Result<usize, WalletError> {
+        let descriptor = self
+            .indexed_graph
+            .index
+            .keychains()
+            .get(keychain)
+            .ok_or_else(|| WalletError::MissingKeychain(keychain.clone()))?;
+
+        Ok(descriptor.max_weight_to_satisfy()?)
     }
 
     pub fn get_address(&self) -> Result<Address, WalletError> {
@@ -400,93 +1148,1057 @@ impl WalletStore {
         let block_info = rpc.get_block_info(block_hash)?;
         let height = block_info.height as u32;
 
-        let changeset = self.indexed_graph.apply_block_relevant(&block, height);
+        let mut changeset = self.reveal_lookahead_matches(&block.txdata)?;
+        changeset.append(self.apply_block(&block, height)?);
+
+        self.persist.stage_and_commit(changeset)
+    }
 
-        self.indexed_graph.apply_changeset(changeset.clone());
+    /// Brings the wallet up to bitcoind's tip, one block at a time, starting after the
+    /// last checkpoint. Checkpoints that are no longer in the active chain are
+    /// disconnected first, so the walk resumes from the fork point after a reorg.
+    pub fn sync(&mut self) -> Result<Option<Changeset>, WalletError> {
+        let from_height = self.latest_checkpoint().height + 1;
 
-        self.persist
-            .stage_and_commit(Changeset::try_from(changeset)?)
+        let mut lookahead = std::mem::take(&mut self.lookahead);
+        let scanned = lookahead
+            .refresh(&self.indexed_graph.index)
+            .and_then(|_| self.scan_blocks(from_height, &mut lookahead, |_, _| {}));
+        self.lookahead = lookahead;
+
+        // blocks applied before a failed RPC call are in memory already, write them too
+        let changeset = self.persist.commit()?;
+        scanned?;
+
+        Ok(changeset)
     }
 
-    pub fn get_utxos(
+    /// Replays every block from `from_height` (the wallet birthday if `None`) to the tip,
+    /// revealing script pubkeys of each keychain as long as payments are found within
+    /// `stop_gap` indices of the last revealed one. `progress` is called with the
+    /// height of each scanned block and the tip height.
+    pub fn full_scan(
         &mut self,
-        keychain: KeychainKind,
-    ) -> Result<Vec<(OutPoint, &TxOut)>, WalletError> {
-        let tx_graph = self.indexed_graph.graph();
+        from_height: Option<u32>,
+        stop_gap: BTreeMap<KeychainKind, u32>,
+        progress: impl FnMut(u32, u32),
+    ) -> Result<Option<Changeset>, WalletError> {
+        let from_height = match from_height {
+            Some(height) => height,
+            None => self.birthday_height()?.unwrap_or(0),
+        };
+
+        let mut window = SpkWindow::new(stop_gap);
+        window.refresh(&self.indexed_graph.index)?;
 
-        let chain_tip = self.oracle.get_chain_tip().unwrap();
+        // the genesis block has no parent to link to, and its coinbase is unspendable anyway
+        let scanned = self.scan_blocks(from_height.max(1), &mut window, progress);
 
-        let all_utxos = tx_graph
-            .all_txouts()
-            .filter(|(outpoint, _)| {
-                match tx_graph.try_get_chain_spend::<Oracle>(&self.oracle, chain_tip, *outpoint) {
-                    Ok(Some(_)) => false,
-                    _ => true,
+        let changeset = self.persist.commit()?;
+        scanned?;
+
+        Ok(changeset)
+    }
+
+    /// Stages the changes of every block as it is applied, so the caller can commit the
+    /// blocks scanned so far even if a later one fails.
+    // `next_height` is read again when a reorg restarts the `'scan` loop
+    #[allow(clippy::mut_range_bound)]
+    fn scan_blocks(
+        &mut self,
+        from_height: u32,
+        window: &mut SpkWindow,
+        mut progress: impl FnMut(u32, u32),
+    ) -> Result<(), WalletError> {
+        let mut next_height = from_height;
+
+        'scan: loop {
+            // drops hashes cached for an older tip, which stale checkpoints could still match
+            let node_tip = self.oracle.get_chain_tip()?;
+
+            let stale_blocks = self.disconnect_stale_blocks()?;
+
+            // after a reorg, resume from the fork point if we got past it
+            if let Some(fork_height) = stale_blocks.local_chain.keys().next() {
+                next_height = next_height.min(*fork_height);
+            }
+            self.persist.stage(stale_blocks);
+
+            let mut prev_hash = self.checkpoint_hash_at(next_height - 1);
+
+            for height in next_height..=node_tip.height {
+                let block_hash = match self.oracle.block_hash_at(height)? {
+                    Some(block_hash) => block_hash,
+                    None => {
+                        self.oracle.clear_cache();
+                        continue 'scan;
+                    }
+                };
+
+                let block = self.oracle.rpc.get_block(&block_hash)?;
+
+                // the node switched chains while we were walking it
+                if prev_hash.is_some_and(|prev_hash| block.header.prev_blockhash != prev_hash) {
+                    self.oracle.clear_cache();
+                    continue 'scan;
                 }
-            })
-            .collect::<Vec<(OutPoint, &TxOut)>>();
 
-        let revealed_spks = (0..=self
-            .indexed_graph
-            .index
-            .last_revealed_index(&keychain)
-            .unwrap_or(0))
-            .map(|index| {
-                self.indexed_graph
+                let revealed = self.reveal_window_matches(window, &block.txdata)?;
+                self.persist.stage(revealed);
+                let applied = self.apply_block(&block, height)?;
+                self.persist.stage(applied);
+
+                progress(height, node_tip.height);
+
+                prev_hash = Some(block_hash);
+                next_height = height + 1;
+            }
+
+            return Ok(());
+        }
+    }
+
+    /// Reveals the keychain indices in `window` that `txs` pay to, so the graph picks up
+    /// those outputs when the transactions are applied.
+    fn reveal_window_matches<'t>(
+        &mut self,
+        window: &mut SpkWindow,
+        txs: impl IntoIterator<Item = &'t Transaction>,
+    ) -> Result<Changeset, WalletError> {
+        let mut changeset = keychain::ChangeSet::default();
+
+        for tx in txs {
+            for (keychain, derivation_index) in window.matches(tx) {
+                let (_, reveal_changeset) = self
+                    .indexed_graph
                     .index
-                    .spk_at_index(keychain.clone(), index)
+                    .reveal_to_target(&keychain, derivation_index);
+
+                if !reveal_changeset.is_empty() {
+                    changeset.append(reveal_changeset);
+                    window.refresh(&self.indexed_graph.index)?;
+                }
+            }
+        }
+
+        Ok(Changeset::from(indexed_tx_graph::ChangeSet::from(
+            changeset,
+        )))
+    }
+
+    fn reveal_lookahead_matches<'t>(
+        &mut self,
+        txs: impl IntoIterator<Item = &'t Transaction>,
+    ) -> Result<Changeset, WalletError> {
+        let mut lookahead = std::mem::take(&mut self.lookahead);
+        let changeset = lookahead
+            .refresh(&self.indexed_graph.index)
+            .and_then(|_| self.reveal_window_matches(&mut lookahead, txs));
+        self.lookahead = lookahead;
+
+        changeset
+    }
+
+    fn checkpoint_hash_at(&self, height: u32) -> Option<BlockHash> {
+        self.chain
+            .tip()
+            .iter()
+            .take_while(|checkpoint| checkpoint.height() >= height)
+            .find(|checkpoint| checkpoint.height() == height)
+            .map(|checkpoint| checkpoint.hash())
+    }
+
+    pub fn birthday(&self) -> Option<Birthday> {
+        self.birthday
+    }
+
+    pub fn set_birthday(&mut self, birthday: Birthday) -> Result<Option<Changeset>, WalletError> {
+        self.birthday = Some(birthday);
+
+        self.persist.stage_and_commit(Changeset {
+            birthday: Some(birthday),
+            ..Default::default()
+        })
+    }
+
+    /// Keeps coin selection from spending `outpoint`, e.g. a fidelity bond or dust sent
+    /// by someone else. It can still be spent with `TxBuilder::add_utxo`.
+    pub fn freeze(&mut self, outpoint: OutPoint) -> Result<(), WalletError> {
+        self.set_frozen(outpoint, true)
+    }
+
+    pub fn unfreeze(&mut self, outpoint: OutPoint) -> Result<(), WalletError> {
+        self.set_frozen(outpoint, false)
+    }
+
+    fn set_frozen(&mut self, outpoint: OutPoint, frozen: bool) -> Result<(), WalletError> {
+        let changed = match frozen {
+            true => self.frozen.insert(outpoint),
+            false => self.frozen.remove(&outpoint),
+        };
+        if !changed {
+            return Ok(());
+        }
+
+        self.persist.stage_and_commit(Changeset {
+            frozen: BTreeMap::from([(outpoint, frozen)]),
+            ..Default::default()
+        })?;
+
+        Ok(())
+    }
+
+    pub fn is_frozen(&self, outpoint: &OutPoint) -> bool {
+        self.frozen.contains(outpoint)
+    }
+
+    pub fn frozen(&self) -> &BTreeSet<OutPoint> {
+        &self.frozen
+    }
+
+    /// Labels are keyed by the canonical form of their reference, so the same txid or
+    /// address is found however it was written.
+    fn label_key(
+        &self,
+        label_type: LabelType,
+        reference: &str,
+    ) -> Result<(LabelType, String), WalletError> {
+        let reference = label_type
+            .normalize_reference(reference, self.network)
+            .ok_or_else(|| WalletError::InvalidLabelReference {
+                label_type,
+                reference: reference.to_string(),
+            })?;
+
+        Ok((label_type, reference))
+    }
+
+    /// Labels what `reference` points to: a txid, an address, a pubkey, an outpoint or an
+    /// xpub, depending on `label_type`. A `None` label removes it.
+    pub fn set_label(
+        &mut self,
+        label_type: LabelType,
+        reference: impl ToString,
+        label: Option<&str>,
+    ) -> Result<(), WalletError> {
+        let key = self.label_key(label_type, &reference.to_string())?;
+        let label = label.map(|label| Label {
+            label: label.to_string(),
+            origin: None,
+        });
+
+        match &label {
+            Some(label) => self.labels.insert(key.clone(), label.clone()),
+            None => self.labels.remove(&key),
+        };
+
+        self.persist.stage_and_commit(Changeset {
+            labels: BTreeMap::from([(key, label)]),
+            ..Default::default()
+        })?;
+
+        Ok(())
+    }
+
+    pub fn label(
+        &self,
+        label_type: LabelType,
+        reference: impl ToString,
+    ) -> Result<Option<&str>, WalletError> {
+        let key = self.label_key(label_type, &reference.to_string())?;
+
+        Ok(self.labels.get(&key).map(|label| label.label.as_str()))
+    }
+
+    pub fn labels(&self) -> &BTreeMap<(LabelType, String), Label> {
+        &self.labels
+    }
+
+    /// Reads BIP329 records, one JSON object per line. Labels replace the ones already
+    /// set, and outputs that are not `spendable` are frozen. Returns the number of records,
+    /// or `InvalidLabelReference` if a reference is not of its record's type.
+    pub fn import_labels<R: BufRead>(&mut self, reader: R) -> Result<usize, WalletError> {
+        // nothing is applied unless every line is valid
+        let mut changeset = Changeset::default();
+        let mut imported = 0;
+
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let record: Bip329Record = serde_json::from_str(&line)?;
+
+            let key = self.label_key(record.label_type, &record.reference)?;
+
+            if let (LabelType::Output, Some(spendable)) = (record.label_type, record.spendable) {
+                let outpoint = OutPoint::from_str(&key.1)?;
+                changeset.frozen.insert(outpoint, !spendable);
+            }
+
+            if let Some(label) = record.label {
+                let label = Label {
+                    label,
+                    origin: record.origin,
+                };
+                changeset.labels.insert(key, Some(label));
+            }
+
+            imported += 1;
+        }
+
+        for (outpoint, frozen) in &changeset.frozen {
+            match frozen {
+                true => self.frozen.insert(*outpoint),
+                false => self.frozen.remove(outpoint),
+            };
+        }
+        for (key, label) in &changeset.labels {
+            if let Some(label) = label {
+                self.labels.insert(key.clone(), label.clone());
+            }
+        }
+
+        self.persist.stage_and_commit(changeset)?;
+
+        Ok(imported)
+    }
+
+    /// Writes every label as a BIP329 record, one JSON object per line, along with the
+    /// frozen outputs. Returns the number of records.
+    pub fn export_labels<W: Write>(&self, mut writer: W) -> Result<usize, WalletError> {
+        let mut frozen = self
+            .frozen
+            .iter()
+            .map(|outpoint| outpoint.to_string())
+            .collect::<BTreeSet<_>>();
+
+        let mut records = vec![];
+        for ((label_type, reference), label) in &self.labels {
+            let spendable = match label_type {
+                LabelType::Output => Some(!frozen.remove(reference)),
+                _ => None,
+            };
+
+            records.push(Bip329Record {
+                label_type: *label_type,
+                reference: reference.clone(),
+                label: Some(label.label.clone()),
+                origin: label.origin.clone(),
+                spendable,
+            });
+        }
+
+        // frozen outputs without a label still have to stay frozen on import
+        for reference in frozen {
+            records.push(Bip329Record {
+                label_type: LabelType::Output,
+                reference,
+                label: None,
+                origin: None,
+                spendable: Some(false),
+            });
+        }
+
+        for record in &records {
+            writeln!(writer, "{}", serde_json::to_string(record)?)?;
+        }
+
+        Ok(records.len())
+    }
+
+    /// Height of the first block that can contain wallet transactions, according to
+    /// the birthday.
+    pub fn birthday_height(&self) -> Result<Option<u32>, WalletError> {
+        let birthday_time = match self.birthday {
+            None => return Ok(None),
+            Some(Birthday::Height(height)) => return Ok(Some(height)),
+            Some(Birthday::Time(time)) => time.saturating_sub(MAX_FUTURE_BLOCK_TIME),
+        };
+
+        // block times are only roughly ordered, search for the first one past the birthday
+        let mut low = 0;
+        let mut high = self.oracle.get_chain_tip()?.height;
+
+        while low < high {
+            let middle = low + (high - low) / 2;
+
+            let block_time = match self.oracle.block_hash_at(middle)? {
+                Some(block_hash) => self.oracle.rpc.get_block_header(&block_hash)?.time as u64,
+                None => u64::MAX,
+            };
+
+            if block_time < birthday_time {
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+
+        Ok(Some(low))
+    }
+
+    fn apply_block(&mut self, block: &Block, height: u32) -> Result<Changeset, WalletError> {
+        let indexed_tx_graph = self.indexed_graph.apply_block_relevant(block, height);
+
+        let local_chain = self.chain.insert_block(BlockId {
+            height,
+            hash: block.block_hash(),
+        })?;
+
+        Ok(Changeset {
+            indexed_tx_graph,
+            local_chain,
+            ..Default::default()
+        })
+    }
+
+    /// Last revealed index and lookahead of each keychain, which decide the script
+    /// pubkeys a transaction is matched against.
+    fn watched_spks(&self) -> BTreeMap<KeychainKind, (Option<u32>, u32)> {
+        let index = &self.indexed_graph.index;
+
+        index
+            .keychains()
+            .keys()
+            .map(|keychain| {
+                let last_revealed = index.last_revealed_index(keychain);
+                (
+                    keychain.clone(),
+                    (last_revealed, self.lookahead.gap(keychain)),
+                )
             })
-            .filter(|spk| spk.is_some())
-            .map(|spk| spk.unwrap())
-            .collect::<Vec<&Script>>();
+            .collect()
+    }
 
-        println!("revealed_spks: {:#?}", revealed_spks);
-        println!("all_utxos: {:#?}", all_utxos);
+    /// Inserts the wallet-relevant transactions of bitcoind's mempool as unconfirmed,
+    /// seen now. Unconfirmed wallet transactions that are no longer in the mempool
+    /// are recorded as evicted until the node relays them again.
+    pub fn sync_mempool(&mut self) -> Result<Option<Changeset>, WalletError> {
+        let seen_at = unix_now();
+        let mempool = self
+            .oracle
+            .rpc
+            .get_raw_mempool()?
+            .into_iter()
+            .collect::<BTreeSet<Txid>>();
+
+        // only txids that are still in the mempool can show up again, and only while the
+        // wallet watches the same script pubkeys. Added keychains, reveals and lookahead
+        // changes can make an inspected tx relevant.
+        let watch = self.watched_spks();
+        if watch != self.inspected_mempool_watch {
+            self.inspected_mempool_txids.clear();
+        }
+        self.inspected_mempool_txids
+            .retain(|txid| mempool.contains(txid));
+        // txs of this poll were checked before its own reveals, the next poll checks again
+        self.inspected_mempool_watch = watch;
+
+        let mut mempool_txs = vec![];
+        for txid in &mempool {
+            let tx = match self.indexed_graph.graph().get_tx(*txid) {
+                Some(tx) => tx.clone(),
+                None if self.inspected_mempool_txids.contains(txid) => continue,
+                None => match self.oracle.mempool_tx(txid)? {
+                    Some(tx) => tx,
+                    None => continue,
+                },
+            };
+
+            // make sure a tx that comes back after being evicted is newer than its eviction
+            let seen_at = match self.mempool_evictions.get(txid) {
+                Some(evicted_at) => seen_at.max(*evicted_at),
+                None => seen_at,
+            };
+
+            mempool_txs.push((tx, seen_at));
+        }
+
+        let mut changeset = self.reveal_lookahead_matches(mempool_txs.iter().map(|(tx, _)| tx))?;
+
+        changeset.append(Changeset::from(
+            self.indexed_graph.batch_insert_relevant_unconfirmed(
+                mempool_txs.iter().map(|(tx, seen_at)| (tx, *seen_at)),
+            ),
+        ));
 
-        let utxos = all_utxos
+        let graph = self.indexed_graph.graph();
+        self.inspected_mempool_txids.extend(
+            mempool_txs
+                .iter()
+                .map(|(tx, _)| tx.txid())
+                .filter(|txid| graph.get_tx(*txid).is_none()),
+        );
+
+        let chain_tip = self.oracle.get_chain_tip()?;
+        let mut mempool_evictions = BTreeMap::new();
+
+        for tx in self.indexed_graph.graph().full_txs() {
+            if mempool.contains(&tx.txid) || self.is_evicted(&tx.txid) {
+                continue;
+            }
+
+            let chain_position = self.indexed_graph.graph().try_get_chain_position(
+                &self.oracle,
+                chain_tip,
+                tx.txid,
+            )?;
+
+            if let Some(ChainPosition::Unconfirmed(last_seen)) = chain_position {
+                mempool_evictions.insert(tx.txid, seen_at.max(last_seen + 1));
+            }
+        }
+
+        self.mempool_evictions.extend(mempool_evictions.clone());
+
+        changeset.append(Changeset {
+            mempool_evictions,
+            ..Default::default()
+        });
+
+        self.persist.stage_and_commit(changeset)
+    }
+
+    /// Whether an unconfirmed transaction dropped out of the node's mempool after it
+    /// was last seen there. A transaction mined before `sync` caught up with it is
+    /// recorded as evicted, so one anchored in the wallet's chain never counts as such.
+    pub fn is_evicted(&self, txid: &Txid) -> bool {
+        let evicted_at = match self.mempool_evictions.get(txid) {
+            Some(evicted_at) => *evicted_at,
+            None => return false,
+        };
+
+        let tx_node = match self.indexed_graph.graph().get_tx_node(*txid) {
+            Some(tx_node) => tx_node,
+            None => return false,
+        };
+
+        let chain_tip = self.chain.tip().block_id();
+        let confirmed = tx_node.anchors.iter().any(|anchor| {
+            matches!(
+                self.chain.is_block_in_chain(anchor.anchor_block, chain_tip),
+                Ok(Some(true))
+            )
+        });
+
+        !confirmed && evicted_at > tx_node.last_seen_unconfirmed
+    }
+
+    /// What the wallet's unspent outputs add up to, per keychain. Unconfirmed outputs are
+    /// trusted if they are our own change.
+    pub fn balance(&self) -> Result<WalletBalance, WalletError> {
+        let graph = self.indexed_graph.graph();
+        let chain_tip = self.oracle.get_chain_tip()?;
+
+        let mut per_keychain = BTreeMap::<KeychainKind, Balance>::new();
+        for ((keychain, _), outpoint) in self.indexed_graph.index.outpoints() {
+            let Some(txout) = graph.get_txout(*outpoint) else {
+                continue;
+            };
+
+            let chain_position =
+                match graph.try_get_chain_position(&self.oracle, chain_tip, outpoint.txid)? {
+                    Some(chain_position) => chain_position,
+                    None => continue,
+                };
+
+            if self.is_evicted(&outpoint.txid) || self.is_spent(chain_tip, *outpoint)? {
+                continue;
+            }
+
+            let balance = per_keychain.entry(keychain.clone()).or_default();
+            match chain_position {
+                ChainPosition::Confirmed(anchor) => {
+                    let is_coinbase = graph
+                        .get_tx(outpoint.txid)
+                        .is_some_and(|tx| tx.is_coin_base());
+
+                    if is_coinbase
+                        && chain_tip.height + 1 < anchor.confirmation_height + COINBASE_MATURITY
+                    {
+                        balance.immature += txout.value;
+                    } else {
+                        balance.confirmed += txout.value;
+                    }
+                }
+                ChainPosition::Unconfirmed(_) if *keychain == KeychainKind::Internal => {
+                    balance.trusted_pending += txout.value;
+                }
+                ChainPosition::Unconfirmed(_) => balance.untrusted_pending += txout.value,
+            }
+        }
+
+        let mut total = Balance::default();
+        for balance in per_keychain.values() {
+            total.immature += balance.immature;
+            total.trusted_pending += balance.trusted_pending;
+            total.untrusted_pending += balance.untrusted_pending;
+            total.confirmed += balance.confirmed;
+        }
+
+        Ok(WalletBalance {
+            total,
+            per_keychain,
+        })
+    }
+
+    /// Up to `limit` wallet transactions, most recent first, skipping the first `offset`.
+    /// Transactions creating or spending one of `fidelity_bonds` are marked as such.
+    pub fn transactions(
+        &self,
+        fidelity_bonds: &[FidelityBond],
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<TxHistoryItem>, WalletError> {
+        let graph = self.indexed_graph.graph();
+        let index = &self.indexed_graph.index;
+        let chain_tip = self.oracle.get_chain_tip()?;
+
+        let mut history = vec![];
+        for canonical_tx in graph.try_list_chain_txs(&self.oracle, chain_tip) {
+            let canonical_tx = canonical_tx?;
+            let txid = canonical_tx.tx_node.txid;
+            let tx = canonical_tx.tx_node.tx;
+
+            if self.is_evicted(&txid) {
+                continue;
+            }
+
+            let mut net = BTreeMap::<KeychainKind, i64>::new();
+            for txin in &tx.input {
+                if let Some(txout) = graph.get_txout(txin.previous_output) {
+                    if let Some((keychain, _)) = index.index_of_spk(&txout.script_pubkey) {
+                        *net.entry(keychain.clone()).or_default() -= txout.value as i64;
+                    }
+                }
+            }
+            for txout in &tx.output {
+                if let Some((keychain, _)) = index.index_of_spk(&txout.script_pubkey) {
+                    *net.entry(keychain.clone()).or_default() += txout.value as i64;
+                }
+            }
+
+            let is_fidelity = fidelity_bonds.iter().any(|bond| {
+                bond.outpoint.txid == txid
+                    || tx
+                        .input
+                        .iter()
+                        .any(|txin| txin.previous_output == bond.outpoint)
+            });
+            let is_swap = net.keys().any(|keychain| {
+                matches!(
+                    keychain,
+                    KeychainKind::IncomingSwapCoins(_) | KeychainKind::OutgoingSwapCoins(_)
+                )
+            });
+            let kind = match (is_fidelity, is_swap) {
+                (true, _) => TxKind::Fidelity,
+                (false, true) => TxKind::Swap,
+                (false, false) => TxKind::Payment,
+            };
+
+            let (confirmation_height, confirmation_time, sort_key) =
+                match canonical_tx.chain_position {
+                    ChainPosition::Confirmed(anchor) => (
+                        Some(anchor.confirmation_height),
+                        Some(anchor.confirmation_time),
+                        (0, anchor.confirmation_height as u64),
+                    ),
+                    ChainPosition::Unconfirmed(last_seen) => (None, None, (1, last_seen)),
+                };
+
+            let item = TxHistoryItem {
+                txid,
+                confirmation_height,
+                confirmation_time,
+                net,
+                fee: self.fee_of(tx).ok(),
+                kind,
+            };
+
+            history.push((sort_key, item));
+        }
+
+        // pending transactions first, then confirmed ones from the tip down
+        history.sort_by(|(a, a_item), (b, b_item)| {
+            b.cmp(a).then_with(|| a_item.txid.cmp(&b_item.txid))
+        });
+
+        Ok(history
             .into_iter()
-            .filter(|utxo| revealed_spks.contains(&utxo.1.script_pubkey.as_script()))
-            .collect::<Vec<(OutPoint, &TxOut)>>();
+            .map(|(_, item)| item)
+            .skip(offset)
+            .take(limit)
+            .collect())
+    }
+
+    /// Whether `outpoint` is spent by a transaction that is confirmed or still in the
+    /// mempool.
+    fn is_spent(&self, chain_tip: BlockId, outpoint: OutPoint) -> Result<bool, WalletError> {
+        let chain_spend =
+            self.indexed_graph
+                .graph()
+                .try_get_chain_spend(&self.oracle, chain_tip, outpoint)?;
+
+        match chain_spend {
+            Some((ChainPosition::Unconfirmed(_), txid)) => Ok(!self.is_evicted(&txid)),
+            Some(_) => Ok(true),
+            None => Ok(false),
+        }
+    }
+
+    /// Wallet transactions that are waiting in the mempool, with the time they were last
+    /// seen there.
+    pub fn pending_transactions(&self) -> Result<Vec<(&Transaction, u64)>, WalletError> {
+        let chain_tip = self.oracle.get_chain_tip()?;
+        let mut pending = vec![];
+
+        for canonical_tx in self
+            .indexed_graph
+            .graph()
+            .try_list_chain_txs(&self.oracle, chain_tip)
+        {
+            let canonical_tx = canonical_tx?;
+
+            if let ChainPosition::Unconfirmed(last_seen) = canonical_tx.chain_position {
+                if !self.is_evicted(&canonical_tx.tx_node.txid) {
+                    pending.push((canonical_tx.tx_node.tx, last_seen));
+                }
+            }
+        }
+
+        Ok(pending)
+    }
+
+    /// Removes every checkpoint above the highest one that bitcoind still has in its
+    /// active chain. Transactions anchored in the removed blocks become unconfirmed.
+    fn disconnect_stale_blocks(&mut self) -> Result<Changeset, WalletError> {
+        let mut stale_blocks = local_chain::ChangeSet::new();
+
+        for checkpoint in self.chain.tip().iter() {
+            if self.oracle.block_hash_at(checkpoint.height())? == Some(checkpoint.hash()) {
+                break;
+            }
+
+            stale_blocks.insert(checkpoint.height(), None);
+        }
+
+        if stale_blocks.is_empty() {
+            return Ok(Changeset::default());
+        }
+
+        self.chain.apply_changeset(&stale_blocks)?;
+
+        Ok(Changeset::from(stale_blocks))
+    }
+
+    /// The most recent block the wallet has processed.
+    pub fn latest_checkpoint(&self) -> BlockId {
+        self.chain.tip().block_id()
+    }
 
-        println!("utxos: {:?}", utxos);
+    /// Unspent outputs of `keychain`.
+    pub fn get_utxos(&self, keychain: KeychainKind) -> Result<Vec<LocalUtxo>, WalletError> {
+        self.unspent_outputs(Some(&keychain))
+    }
+
+    /// Unspent outputs of every keychain.
+    pub fn list_unspent(&self) -> Result<Vec<LocalUtxo>, WalletError> {
+        self.unspent_outputs(None)
+    }
+
+    fn unspent_outputs(
+        &self,
+        keychain: Option<&KeychainKind>,
+    ) -> Result<Vec<LocalUtxo>, WalletError> {
+        let chain_tip = self.oracle.get_chain_tip()?;
+
+        let outpoints = self
+            .indexed_graph
+            .index
+            .outpoints()
+            .iter()
+            .filter(|((output_keychain, _), _)| keychain.is_none_or(|k| k == output_keychain))
+            .cloned();
+
+        let mut utxos = vec![];
+        for txout in
+            self.indexed_graph
+                .graph()
+                .try_filter_chain_txouts(&self.oracle, chain_tip, outpoints)
+        {
+            let ((keychain, derivation_index), full_txout) = txout?;
+
+            if self.is_evicted(&full_txout.outpoint.txid) {
+                continue;
+            }
+
+            // the graph still counts spends that dropped out of the mempool
+            match full_txout.spent_by {
+                Some((ChainPosition::Unconfirmed(_), txid)) if self.is_evicted(&txid) => {}
+                Some(_) => continue,
+                None => {}
+            }
+
+            let confirmation_height = match full_txout.chain_position {
+                ChainPosition::Confirmed(anchor) => Some(anchor.confirmation_height),
+                ChainPosition::Unconfirmed(_) => None,
+            };
+
+            utxos.push(LocalUtxo {
+                outpoint: full_txout.outpoint,
+                txout: full_txout.txout,
+                keychain,
+                derivation_index,
+                confirmation_height,
+                frozen: self.frozen.contains(&full_txout.outpoint),
+            });
+        }
 
         Ok(utxos)
     }
 }
 
-pub struct KeychainStore {
-    pub file_name: PathBuf,
-    path: PathBuf,
-}
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use bdk_chain::indexed_tx_graph::Indexer;
+    use bitcoin::{absolute::LockTime, bip32::ExtendedPrivKey, ScriptBuf, TxIn};
+    use bitcoind::bitcoincore_rpc::Auth;
+
+    use super::*;
+
+    fn descriptor(seed: u8) -> String {
+        let xprv = ExtendedPrivKey::new_master(Network::Regtest, &[seed; 32]).unwrap();
+        format!("wpkh({}/0/*)", xprv)
+    }
+
+    /// Opens the wallet in `dir`, the node is never contacted.
+    fn open(dir: &Path, passphrase: Option<&str>, birthday: Option<Birthday>) -> WalletStore {
+        let rpc = Client::new("http://localhost:1", Auth::None).unwrap();
+        let descriptor = descriptor(1);
+        let descriptors = BTreeMap::from([(KeychainKind::External, descriptor.as_str())]);
+
+        WalletStore::new(
+            Network::Regtest,
+            descriptors,
+            rpc,
+            PathBuf::from("wallet"),
+            dir.to_path_buf(),
+            BackendKind::File,
+            passphrase,
+            birthday,
+        )
+        .unwrap()
+    }
 
-impl PersistBackend<Changeset> for KeychainStore {
-    type LoadError = WalletError;
-    type WriteError = WalletError;
+    /// A transaction of `vsize` vbytes, one segwit input and a 22 byte output script.
+    fn tx_of_vsize(vsize: usize) -> Transaction {
+        let mut tx = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn::default()],
+            output: vec![TxOut {
+                value: 0,
+                script_pubkey: ScriptBuf::from(vec![0; 22]),
+            }],
+        };
+        tx.input[0].witness.push([0; 72]);
+        let padding = vsize - tx.vsize();
+        tx.output[0].script_pubkey = ScriptBuf::from(vec![0; 22 + padding]);
+        assert_eq!(tx.vsize(), vsize);
+        tx
+    }
 
-    fn write_changes(&mut self, changeset: &Changeset) -> Result<(), Self::WriteError> {
-        let path = self.path.join(&self.file_name);
+    #[test]
+    fn replacement_pays_for_evicted_descendants() {
+        let original = tx_of_vsize(150);
+        let replacement = tx_of_vsize(150);
+
+        // beats the original alone, not together with a child that paid 1000
+        assert!(check_replacement(&original, 300, 300, 1, &replacement, 600).is_ok());
+        assert!(matches!(
+            check_replacement(&original, 300, 1300, 2, &replacement, 600),
+            Err(Bip125Error::InsufficientFee {
+                needed: 1450,
+                paid: 600
+            })
+        ));
+        assert!(check_replacement(&original, 300, 1300, 2, &replacement, 1450).is_ok());
 
-        let prefix = path.parent().unwrap();
-        std::fs::create_dir_all(prefix).unwrap();
+        assert!(matches!(
+            check_replacement(&original, 300, 300, 101, &replacement, 100_000),
+            Err(Bip125Error::TooManyReplacements(101))
+        ));
+    }
 
-        let wallet_file = OpenOptions::new().write(true).create(true).open(&path)?;
+    #[test]
+    fn import_labels_rejects_mismatched_references() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let mut wallet = open(dir.path(), None, None);
+        let txid = "117dd7ace4b3d88526b16acde9a952b41e9d21560f1b11199e1bfdfc57e1e5f8";
+
+        for (label_type, reference) in [
+            ("tx", format!("{}:0", txid)),
+            ("output", txid.to_string()),
+            ("input", format!("{}:x", txid)),
+            // mainnet address in a regtest wallet
+            (
+                "addr",
+                "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq".to_string(),
+            ),
+            ("pubkey", "02".to_string()),
+            ("xpub", txid.to_string()),
+        ] {
+            let valid = format!(r#"{{"type":"tx","ref":"{}","label":"kept out"}}"#, txid);
+            let invalid = format!(
+                r#"{{"type":"{}","ref":"{}","label":"bad"}}"#,
+                label_type, reference
+            );
+            let lines = format!("{}\n{}\n", valid, invalid);
+
+            assert!(matches!(
+                wallet.import_labels(lines.as_bytes()),
+                Err(WalletError::InvalidLabelReference { .. })
+            ));
+        }
+        assert!(wallet.labels().is_empty());
+
+        // references are stored in canonical form
+        let uppercase = format!(
+            r#"{{"type":"output","ref":"{}:1","spendable":false}}"#,
+            txid.to_uppercase()
+        );
+        assert_eq!(wallet.import_labels(uppercase.as_bytes()).unwrap(), 1);
+        assert!(wallet.is_frozen(&OutPoint::from_str(&format!("{}:1", txid)).unwrap()));
+    }
 
-        let writer = BufWriter::new(wallet_file);
-        Ok(serde_cbor::to_writer(writer, &changeset)?)
+    #[test]
+    fn set_label_shares_keys_with_import() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wallet = open(dir.path(), None, None);
+        let txid = "117dd7ace4b3d88526b16acde9a952b41e9d21560f1b11199e1bfdfc57e1e5f8";
+
+        wallet
+            .set_label(LabelType::Tx, txid.to_uppercase(), Some("set"))
+            .unwrap();
+        let imported = format!(r#"{{"type":"tx","ref":"{}","label":"imported"}}"#, txid);
+        wallet.import_labels(imported.as_bytes()).unwrap();
+
+        assert_eq!(wallet.labels().len(), 1);
+        assert_eq!(
+            wallet.label(LabelType::Tx, txid.to_uppercase()).unwrap(),
+            Some("imported")
+        );
+
+        assert!(matches!(
+            wallet.set_label(LabelType::Tx, format!("{}:0", txid), Some("bad")),
+            Err(WalletError::InvalidLabelReference { .. })
+        ));
+        assert!(matches!(
+            wallet.label(LabelType::Output, txid),
+            Err(WalletError::InvalidLabelReference { .. })
+        ));
     }
 
-    fn load_from_persistence(&mut self) -> Result<Option<Changeset>, Self::LoadError> {
-        //check if the file exists, if not then create it
+    /// Pays 1000 sat to index `derivation_index` of `keychain`.
+    fn payment_to(
+        wallet: &WalletStore,
+        keychain: &KeychainKind,
+        derivation_index: u32,
+    ) -> Transaction {
+        let (_, script_pubkey) = wallet
+            .indexed_graph
+            .index
+            .unbounded_spk_iter(keychain)
+            .nth(derivation_index as usize)
+            .unwrap();
 
-        if !self.path.join(&self.file_name).exists() {
-            return Ok(None);
+        Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn::default()],
+            output: vec![TxOut {
+                value: 1000,
+                script_pubkey,
+            }],
         }
+    }
 
-        let wallet_file = OpenOptions::new()
-            .read(true)
-            .open(&self.path.join(&self.file_name))?;
-        let reader = BufReader::new(wallet_file);
-        let changeset: Changeset = serde_cbor::from_reader(reader)?;
-        Ok(Some(changeset))
+    #[test]
+    fn lookahead_is_configured_per_keychain() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let mut wallet = open(dir.path(), None, None);
+        let swapcoin = KeychainKind::IncomingSwapCoins("swap".to_string());
+        wallet
+            .add_descriptor(swapcoin.clone(), descriptor(2).as_str())
+            .unwrap();
+
+        // past the default windows of 20 and 5
+        let external = payment_to(&wallet, &KeychainKind::External, 20);
+        let swap = payment_to(&wallet, &swapcoin, 5);
+        let changeset = wallet.reveal_lookahead_matches([&external, &swap]).unwrap();
+        assert!(changeset.indexed_tx_graph.indexer.0.is_empty());
+        assert!(!wallet.indexed_graph.index.is_tx_relevant(&external));
+        assert!(!wallet.indexed_graph.index.is_tx_relevant(&swap));
+
+        wallet.set_lookahead(swapcoin.clone(), 6);
+        let changeset = wallet.reveal_lookahead_matches([&external, &swap]).unwrap();
+        assert_eq!(
+            changeset.indexed_tx_graph.indexer.0,
+            BTreeMap::from([(swapcoin, 5)])
+        );
+    }
+
+    #[test]
+    fn keeps_the_first_recorded_birthday() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let wallet = open(dir.path(), None, Some(Birthday::Height(5)));
+        assert_eq!(wallet.birthday(), Some(Birthday::Height(5)));
+        drop(wallet);
+
+        let wallet = open(dir.path(), None, Some(Birthday::Height(9)));
+        assert_eq!(wallet.birthday(), Some(Birthday::Height(5)));
+    }
+
+    #[test]
+    fn restores_signer_of_added_keychain_only_when_encrypted() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let swapcoin = KeychainKind::IncomingSwapCoins("swap".to_string());
+
+        let mut wallet = open(dir.path(), Some("passphrase"), None);
+        wallet
+            .add_descriptor(swapcoin.clone(), descriptor(2).as_str())
+            .unwrap();
+        drop(wallet);
+
+        let mut wallet = open(dir.path(), Some("passphrase"), None);
+        assert!(wallet.signers.contains_key(&swapcoin));
+
+        // decrypting leaves the public descriptor behind, the keychain comes back watch-only
+        wallet.change_passphrase(Some("passphrase"), None).unwrap();
+        drop(wallet);
+
+        let file = std::fs::read(dir.path().join("wallet")).unwrap();
+        let tprv = descriptor(2);
+        let tprv = tprv[5..].split('/').next().unwrap();
+        assert!(!file
+            .windows(tprv.len())
+            .any(|window| window == tprv.as_bytes()));
+
+        let wallet = open(dir.path(), None, None);
+        assert!(wallet
+            .indexed_graph
+            .index
+            .keychains()
+            .contains_key(&swapcoin));
+        assert!(!wallet.signers.contains_key(&swapcoin));
     }
 }
@@ -0,0 +1,377 @@
+use std::collections::BTreeSet;
+
+use bdk_chain::indexed_tx_graph;
+use bitcoin::{
+    absolute::LockTime, psbt::Psbt, script::PushBytes, OutPoint, ScriptBuf, Sequence, Transaction,
+    TxIn, TxOut, Txid, Witness,
+};
+
+use super::{
+    coin_selection::{
+        select_coins, CoinSelectionAlgorithm, CoinSelectionParams, WeightedUtxo, TXIN_BASE_WEIGHT,
+    },
+    error::WalletError,
+    fee_rate::FeeRate,
+    keychain_kind::KeychainKind,
+    store::{Changeset, WalletStore},
+};
+
+const DEFAULT_CONFIRMATION_TARGET: u16 = 6;
+
+/// What a transaction built by `TxBuilder` moves, from the wallet's point of view.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TxDetails {
+    pub txid: Txid,
+    /// Sum of the spent outputs.
+    pub sent: u64,
+    /// Sum of the outputs paying back to the wallet, change included.
+    pub received: u64,
+    pub fee: u64,
+}
+
+/// Builds an unsigned transaction spending the wallet's coins, see `WalletStore::build_tx`.
+pub struct TxBuilder<'a> {
+    store: &'a mut WalletStore,
+    recipients: Vec<(ScriptBuf, u64)>,
+    data: Vec<ScriptBuf>,
+    change_keychain: KeychainKind,
+    utxos: Vec<OutPoint>,
+    unspendable: BTreeSet<OutPoint>,
+    manually_selected_only: bool,
+    drain_to: Option<ScriptBuf>,
+    drain_wallet: bool,
+    fee_rate: FeeRate,
+    coin_selection: CoinSelectionAlgorithm,
+    version: i32,
+    lock_time: LockTime,
+    sequence: Option<Sequence>,
+    replaced: Option<Transaction>,
+    replaced_fee: Option<u64>,
+}
+
+impl<'a> TxBuilder<'a> {
+    pub fn new(store: &'a mut WalletStore) -> Self {
+        Self {
+            store,
+            recipients: vec![],
+            data: vec![],
+            change_keychain: KeychainKind::Internal,
+            utxos: vec![],
+            unspendable: BTreeSet::new(),
+            manually_selected_only: false,
+            drain_to: None,
+            drain_wallet: false,
+            fee_rate: FeeRate::ConfirmationTarget(DEFAULT_CONFIRMATION_TARGET),
+            coin_selection: CoinSelectionAlgorithm::default(),
+            version: 2,
+            lock_time: LockTime::ZERO,
+            sequence: None,
+            replaced: None,
+            replaced_fee: None,
+        }
+    }
+
+    pub fn add_recipient(&mut self, script_pubkey: ScriptBuf, amount: u64) -> &mut Self {
+        self.recipients.push((script_pubkey, amount));
+        self
+    }
+
+    /// Adds an OP_RETURN output carrying `data`.
+    pub fn add_data<T: AsRef<PushBytes>>(&mut self, data: &T) -> &mut Self {
+        self.data.push(ScriptBuf::new_op_return(data));
+        self
+    }
+
+    /// Keychain the change output pays to, `Internal` by default.
+    pub fn change_keychain(&mut self, keychain: KeychainKind) -> &mut Self {
+        self.change_keychain = keychain;
+        self
+    }
+
+    /// Spends `outpoint` whatever coin selection picks. Unlike the coins coin selection
+    /// picks from, it may belong to any keychain, swapcoins included, and may be frozen.
+    pub fn add_utxo(&mut self, outpoint: OutPoint) -> &mut Self {
+        if !self.utxos.contains(&outpoint) {
+            self.utxos.push(outpoint);
+        }
+        self
+    }
+
+    /// Never spends `outpoint`.
+    pub fn add_unspendable(&mut self, outpoint: OutPoint) -> &mut Self {
+        self.unspendable.insert(outpoint);
+        self
+    }
+
+    /// Spends only the coins passed to `add_utxo`.
+    pub fn manually_selected_only(&mut self) -> &mut Self {
+        self.manually_selected_only = true;
+        self
+    }
+
+    /// Sends the change to `script_pubkey` rather than to the change keychain. Together
+    /// with `drain_wallet` this sends everything that is left after the recipients.
+    pub fn drain_to(&mut self, script_pubkey: ScriptBuf) -> &mut Self {
+        self.drain_to = Some(script_pubkey);
+        self
+    }
+
+    /// Spends every coin that coin selection could pick from. Without recipients or
+    /// `drain_to`, this sweeps them to the change keychain.
+    pub fn drain_wallet(&mut self) -> &mut Self {
+        self.drain_wallet = true;
+        self
+    }
+
+    pub fn fee_rate(&mut self, fee_rate: FeeRate) -> &mut Self {
+        self.fee_rate = fee_rate;
+        self
+    }
+
+    pub fn coin_selection(&mut self, coin_selection: CoinSelectionAlgorithm) -> &mut Self {
+        self.coin_selection = coin_selection;
+        self
+    }
+
+    pub fn version(&mut self, version: i32) -> &mut Self {
+        self.version = version;
+        self
+    }
+
+    pub fn nlocktime(&mut self, lock_time: LockTime) -> &mut Self {
+        self.lock_time = lock_time;
+        self
+    }
+
+    /// nSequence of every input. Defaults to one that signals replaceability (BIP125) and
+    /// enables `nlocktime`.
+    pub fn sequence(&mut self, sequence: Sequence) -> &mut Self {
+        self.sequence = Some(sequence);
+        self
+    }
+
+    /// Builds a replacement for `tx`: the coins it spends can be spent again, and no
+    /// unconfirmed coins are added (BIP125 rule 2). `replaced_fee` is what `tx` and the
+    /// descendants evicted with it paid, which the replacement has to exceed.
+    pub(crate) fn replace(&mut self, tx: Transaction, replaced_fee: u64) -> &mut Self {
+        self.replaced = Some(tx);
+        self.replaced_fee = Some(replaced_fee);
+        self
+    }
+
+    pub fn finish(self) -> Result<(Psbt, TxDetails), WalletError> {
+        if self.recipients.is_empty()
+            && self.data.is_empty()
+            && self.drain_to.is_none()
+            && !self.drain_wallet
+        {
+            return Err(WalletError::NoRecipients);
+        }
+
+        let store = self.store;
+
+        let (fee_rate, absolute_fee) = self.fee_rate.resolve(&store.oracle)?;
+
+        let mut available = vec![];
+        for utxo in store.list_unspent()? {
+            if self.unspendable.contains(&utxo.outpoint) {
+                continue;
+            }
+
+            if let Some(replaced) = &self.replaced {
+                // a replacement may not add unconfirmed inputs (BIP125 rule 2), which also
+                // keeps it from spending the outputs of the transaction it replaces
+                if utxo.confirmation_height.is_none() {
+                    continue;
+                }
+
+                // listed as unspent once the original is evicted, added below either way
+                if replaced
+                    .input
+                    .iter()
+                    .any(|txin| txin.previous_output == utxo.outpoint)
+                {
+                    continue;
+                }
+            }
+
+            let satisfaction_weight = store.satisfaction_weight(&utxo.keychain)?;
+            let weighted_utxo = WeightedUtxo::new(utxo.outpoint, utxo.txout, satisfaction_weight);
+            available.push((utxo.keychain, weighted_utxo));
+        }
+
+        if let Some(replaced) = &self.replaced {
+            let graph = store.indexed_graph.graph();
+
+            for txin in &replaced.input {
+                let outpoint = txin.previous_output;
+                let txout = graph
+                    .get_txout(outpoint)
+                    .ok_or(WalletError::UnknownUtxo(outpoint))?;
+                let (keychain, _) = store
+                    .indexed_graph
+                    .index
+                    .index_of_spk(&txout.script_pubkey)
+                    .ok_or(WalletError::UnknownUtxo(outpoint))?;
+
+                let satisfaction_weight = store.satisfaction_weight(&keychain)?;
+                let utxo = WeightedUtxo::new(outpoint, txout.clone(), satisfaction_weight);
+                available.push((keychain.clone(), utxo));
+            }
+        }
+
+        let mut required = vec![];
+        for outpoint in &self.utxos {
+            let position = available
+                .iter()
+                .position(|(_, utxo)| utxo.outpoint == *outpoint)
+                .ok_or(WalletError::UnknownUtxo(*outpoint))?;
+            required.push(available.remove(position).1);
+        }
+
+        // swapcoins and frozen coins are only spent when asked for explicitly
+        let mut optional = available
+            .into_iter()
+            .filter(|(keychain, utxo)| {
+                !self.manually_selected_only
+                    && matches!(keychain, KeychainKind::External | KeychainKind::Internal)
+                    && !store.is_frozen(&utxo.outpoint)
+            })
+            .map(|(_, utxo)| utxo)
+            .collect::<Vec<_>>();
+
+        if self.drain_wallet {
+            required.append(&mut optional);
+        }
+
+        let change_spend_weight =
+            TXIN_BASE_WEIGHT + store.satisfaction_weight(&self.change_keychain)?;
+
+        // the next unused change address, only revealed below if the change output makes
+        // it into the transaction
+        let (change_script, change_index) = match self.drain_to {
+            Some(script_pubkey) => (script_pubkey, None),
+            None => {
+                let index = &store.indexed_graph.index;
+                let unused = index.unused_keychain_spks(&self.change_keychain).next();
+                let (change_index, script_pubkey) = match unused {
+                    Some((change_index, script_pubkey)) => (change_index, script_pubkey.to_owned()),
+                    None => {
+                        let (change_index, _) = index.next_index(&self.change_keychain);
+                        index
+                            .unbounded_spk_iter(&self.change_keychain)
+                            .nth(change_index as usize)
+                            .ok_or(WalletError::MissingKeychain(self.change_keychain.clone()))?
+                    }
+                };
+                (script_pubkey, Some(change_index))
+            }
+        };
+
+        let mut outputs = self
+            .recipients
+            .iter()
+            .map(|(script_pubkey, amount)| TxOut {
+                value: *amount,
+                script_pubkey: script_pubkey.clone(),
+            })
+            .chain(self.data.into_iter().map(|script_pubkey| TxOut {
+                value: 0,
+                script_pubkey,
+            }))
+            .collect::<Vec<_>>();
+
+        let change_output = TxOut {
+            value: 0,
+            script_pubkey: change_script,
+        };
+
+        // the outputs are paid for whichever inputs end up being selected
+        let base_tx = Transaction {
+            version: self.version,
+            lock_time: self.lock_time,
+            input: vec![],
+            output: outputs.clone(),
+        };
+
+        let params = CoinSelectionParams {
+            target: self.recipients.iter().map(|(_, amount)| amount).sum(),
+            fee_rate,
+            absolute_fee,
+            replaced_fee: self.replaced_fee,
+            base_weight: base_tx.weight().to_wu() as usize,
+            change_weight: change_output.weight(),
+            change_spend_weight,
+            dust_limit: change_output.script_pubkey.dust_value().to_sat(),
+        };
+
+        let selection = select_coins(self.coin_selection, required, optional, &params)?;
+
+        match selection.change {
+            Some(change) => outputs.push(TxOut {
+                value: change,
+                ..change_output
+            }),
+            // draining into a dust output would send nothing anywhere
+            None if self.recipients.is_empty() => {
+                return Err(WalletError::InsufficientFunds {
+                    needed: selection.fee + params.dust_limit,
+                    available: selection.selected_amount(),
+                })
+            }
+            None => {}
+        }
+
+        let sequence = self.sequence.unwrap_or(Sequence::ENABLE_RBF_NO_LOCKTIME);
+
+        let tx = Transaction {
+            version: self.version,
+            lock_time: self.lock_time,
+            input: selection
+                .selected
+                .iter()
+                .map(|utxo| TxIn {
+                    previous_output: utxo.outpoint,
+                    script_sig: ScriptBuf::new(),
+                    sequence,
+                    witness: Witness::default(),
+                })
+                .collect(),
+            output: outputs,
+        };
+
+        if let (Some(_), Some(change_index)) = (selection.change, change_index) {
+            let (_, changeset) = store
+                .indexed_graph
+                .index
+                .reveal_to_target(&self.change_keychain, change_index);
+            store
+                .persist
+                .stage_and_commit(Changeset::from(indexed_tx_graph::ChangeSet::from(
+                    changeset,
+                )))?;
+        }
+
+        let received = tx
+            .output
+            .iter()
+            .filter(|txout| {
+                store
+                    .indexed_graph
+                    .index
+                    .index_of_spk(&txout.script_pubkey)
+                    .is_some()
+            })
+            .map(|txout| txout.value)
+            .sum();
+
+        let details = TxDetails {
+            txid: tx.txid(),
+            sent: selection.selected_amount(),
+            received,
+            fee: selection.fee,
+        };
+
+        Ok((Psbt::from_unsigned_tx(tx)?, details))
+    }
+}